@@ -0,0 +1,875 @@
+//! # Audio for the Neotron Desktop BIOS
+//!
+//! The OS writes samples into a ring buffer, and SDL's audio thread takes
+//! them out again whenever the host sound card wants more. If the OS doesn't
+//! keep up, the host plays silence.
+//!
+//! Audio input works the other way around, with samples from the host's
+//! microphone (or a WAV file) going into a buffer for the OS to read.
+//!
+//! The board's codec has three mixer channels - Line Out and Headphones for
+//! output, and Line In for input. We send Headphones to the speakers and Line
+//! Out to the WAV file, and each channel's level scales its samples.
+//!
+//! Instead of playing the samples, we can write them to a WAV file - either
+//! at the rate a sound card would take them, or as fast as the OS can
+//! produce them.
+//!
+//! Eight-bit samples are signed, and sixteen-bit samples are signed and
+//! little-endian. Stereo samples are interleaved, left first.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use pix_engine::prelude::*;
+
+use crate::common;
+use common::audio::{Direction, SampleFormat};
+
+use crate::wav::{WavData, WavWriter};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// A stream of samples between the OS and the host.
+struct Stream {
+	/// The format of the samples in the buffer
+	format: SampleFormat,
+	/// How many sample frames per second
+	sample_rate_hz: u32,
+	/// Bytes in the OS's sample format
+	buffer: VecDeque<u8>,
+	/// Goes up every time the configuration changes
+	generation: u32,
+}
+
+/// Our SDL audio callback, which plays the OS's samples.
+pub struct Playback {
+	/// The format of the samples we expect to find in the buffer
+	format: SampleFormat,
+}
+
+/// One of the codec's mixer channels
+struct MixerChannel {
+	/// The name the OS sees
+	name: &'static str,
+	/// Is this an input or an output?
+	direction: Direction,
+	/// The loudest level. This level leaves the samples unchanged.
+	max_level: u8,
+	/// The level the OS has chosen
+	level: AtomicU8,
+}
+
+/// Our SDL audio callback, which records samples for the OS.
+pub struct Capture {
+	/// How many channels SDL is giving us
+	channels: u8,
+}
+
+/// Writes the audio output to a WAV file, instead of the speakers.
+struct WavSink {
+	/// Where the file goes
+	path: PathBuf,
+	/// Take samples as fast as the OS gives them to us, rather than in real
+	/// time?
+	fast: bool,
+	/// The open file, and the output configuration it was created with. We
+	/// don't create the file until the first samples arrive.
+	writer: Option<(u32, WavWriter)>,
+	/// Have we given up, because of an error or a format change?
+	stopped: bool,
+}
+
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// How many bytes each sample buffer holds.
+///
+/// This is about 85 ms of 16-bit stereo audio at 48 kHz.
+const BUFFER_BYTES: usize = 16384;
+
+/// How many sample frames SDL asks us for at a time.
+const SDL_BUFFER_FRAMES: u16 = 512;
+
+/// How often the WAV sink takes samples, when running in real time.
+const WAV_PERIOD: Duration = Duration::from_millis(10);
+
+/// The mixer channel which sets the level in the WAV file.
+const LINE_OUT: usize = 0;
+
+/// The mixer channel which sets the level from the speakers.
+const HEADPHONES: usize = 1;
+
+/// The mixer channel which sets the level of the audio input.
+const LINE_IN: usize = 2;
+
+/// Our codec's mixer channels.
+static MIXER: [MixerChannel; 3] = [
+	MixerChannel::new("Line Out", Direction::Output),
+	MixerChannel::new("Headphones", Direction::Output),
+	MixerChannel::new("Line In", Direction::Input),
+];
+
+/// The audio going out of the OS, to the speakers.
+static OUTPUT: Mutex<Stream> = Mutex::new(Stream::new());
+
+/// The audio coming in to the OS, from the microphone.
+static INPUT: Mutex<Stream> = Mutex::new(Stream::new());
+
+/// Is the audio input coming from a WAV file, instead of the microphone?
+static INPUT_FROM_WAV: AtomicBool = AtomicBool::new(false);
+
+/// Where the audio output goes instead of the speakers, if anywhere.
+///
+/// Always lock `OUTPUT` first, if you need both.
+static WAV_SINK: Mutex<Option<WavSink>> = Mutex::new(None);
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Change the output sample format and rate.
+///
+/// Any samples still waiting to be played are thrown away.
+pub fn set_output_config(config: common::audio::Config) -> Result<(), common::Error> {
+	let (format, sample_rate_hz) = parse_config(config)?;
+	OUTPUT.lock().unwrap().configure(format, sample_rate_hz);
+	info!("Audio output is {:?} at {} Hz", format, sample_rate_hz);
+	Ok(())
+}
+
+/// Get the output sample format and rate.
+pub fn output_config() -> common::audio::Config {
+	OUTPUT.lock().unwrap().config()
+}
+
+/// Check the OS has given us a configuration we can handle.
+fn parse_config(config: common::audio::Config) -> Result<(SampleFormat, u32), common::Error> {
+	let format = config
+		.sample_format
+		.make_safe()
+		.map_err(|_| common::Error::UnsupportedConfiguration)?;
+	// Newer BIOS API versions may have formats we don't know how to play
+	let known = matches!(
+		format,
+		SampleFormat::EightBitMono
+			| SampleFormat::EightBitStereo
+			| SampleFormat::SixteenBitMono
+			| SampleFormat::SixteenBitStereo
+	);
+	if !known || !(8000..=192000).contains(&config.sample_rate_hz) {
+		return Err(common::Error::UnsupportedConfiguration);
+	}
+	Ok((format, config.sample_rate_hz))
+}
+
+/// Which output configuration are we on?
+///
+/// The playback device must be re-opened when this changes.
+pub fn output_generation() -> u32 {
+	OUTPUT.lock().unwrap().generation
+}
+
+/// Queue samples for playing.
+///
+/// Returns how many bytes we took, which is always a whole number of sample
+/// frames.
+pub fn write_output(samples: &[u8]) -> usize {
+	let mut output = OUTPUT.lock().unwrap();
+	if let Some(sink) = WAV_SINK.lock().unwrap().as_mut().filter(|sink| sink.fast) {
+		// Straight into the file - there's always space
+		let count = samples.len() - (samples.len() % frame_bytes(output.format));
+		sink.write(&output, &samples[0..count]);
+		return count;
+	}
+	output.write(samples)
+}
+
+/// How many bytes can we take right now?
+pub fn output_space() -> usize {
+	let output = OUTPUT.lock().unwrap();
+	let fast = WAV_SINK
+		.lock()
+		.unwrap()
+		.as_ref()
+		.is_some_and(|sink| sink.fast);
+	let space = if fast { BUFFER_BYTES } else { output.space() };
+	space - (space % frame_bytes(output.format))
+}
+
+/// Describe one of the mixer channels.
+///
+/// Returns `None` if there's no such channel.
+pub fn mixer_channel_info(channel: u8) -> Option<common::audio::MixerChannelInfo> {
+	let channel = MIXER.get(usize::from(channel))?;
+	Some(common::audio::MixerChannelInfo {
+		name: common::FfiString::new(channel.name),
+		direction: channel.direction.into(),
+		max_level: channel.max_level,
+		current_level: channel.level.load(Ordering::Relaxed),
+	})
+}
+
+/// Change the level of one of the mixer channels.
+pub fn set_mixer_level(channel: u8, level: u8) -> Result<(), common::Error> {
+	let channel = MIXER
+		.get(usize::from(channel))
+		.ok_or(common::Error::InvalidDevice)?;
+	if level > channel.max_level {
+		return Err(common::Error::UnsupportedConfiguration);
+	}
+	channel.level.store(level, Ordering::Relaxed);
+	info!("Mixer channel {} set to {}", channel.name, level);
+	Ok(())
+}
+
+/// Change the input sample format and rate.
+///
+/// Any samples the OS hasn't read yet are thrown away.
+pub fn set_input_config(config: common::audio::Config) -> Result<(), common::Error> {
+	let (format, sample_rate_hz) = parse_config(config)?;
+	INPUT.lock().unwrap().configure(format, sample_rate_hz);
+	info!("Audio input is {:?} at {} Hz", format, sample_rate_hz);
+	Ok(())
+}
+
+/// Get the input sample format and rate.
+pub fn input_config() -> common::audio::Config {
+	INPUT.lock().unwrap().config()
+}
+
+/// Which input configuration are we on?
+///
+/// Zero means the OS hasn't set one, so it doesn't want any input yet. The
+/// capture device must be re-opened when this changes.
+pub fn input_generation() -> u32 {
+	INPUT.lock().unwrap().generation
+}
+
+/// Should the audio input come from the microphone?
+pub fn capture_enabled() -> bool {
+	!INPUT_FROM_WAV.load(Ordering::Relaxed)
+}
+
+/// Take recorded samples out of the input buffer.
+///
+/// Returns how many bytes we gave, which is always a whole number of sample
+/// frames.
+pub fn read_input(buffer: &mut [u8]) -> usize {
+	let mut input = INPUT.lock().unwrap();
+	let count = buffer.len().min(input.buffer.len());
+	let count = count - (count % frame_bytes(input.format));
+	for (dest, src) in buffer.iter_mut().zip(input.buffer.drain(0..count)) {
+		*dest = src;
+	}
+	count
+}
+
+/// How many bytes of recorded samples are waiting for the OS?
+pub fn input_count() -> usize {
+	INPUT.lock().unwrap().buffer.len()
+}
+
+/// Take the audio input from a WAV file instead of the microphone.
+///
+/// The file starts playing, in real time, when the OS first sets the input
+/// configuration, and is converted to whatever format the OS asks for.
+pub fn start_wav_input(path: &Path) -> std::io::Result<()> {
+	let data = crate::wav::read(path)?;
+	info!(
+		"Reading audio input from: {} ({} channels at {} Hz)",
+		path.display(),
+		data.channels,
+		data.sample_rate_hz
+	);
+	INPUT_FROM_WAV.store(true, Ordering::Relaxed);
+	std::thread::spawn(move || pace_wav_input(data));
+	Ok(())
+}
+
+/// Feed samples from a WAV file into the input buffer at the sample rate.
+///
+/// The file is resampled (by picking the nearest sample) to the rate the OS
+/// asked for. Samples that don't fit in the buffer are lost, as they would be
+/// with a real sound card.
+fn pace_wav_input(data: WavData) {
+	let channels = usize::from(data.channels);
+	let num_frames = data.samples.len() / channels;
+	let mut generation = 0;
+	let mut start = Instant::now();
+	let mut frames_given: u64 = 0;
+	// Where we are in the file, in file frames
+	let mut position = 0.0f64;
+	while (position as usize) < num_frames {
+		std::thread::sleep(WAV_PERIOD);
+		let mut input = INPUT.lock().unwrap();
+		if input.generation == 0 {
+			// The OS doesn't want any input yet
+			continue;
+		}
+		if generation != input.generation {
+			generation = input.generation;
+			start = Instant::now();
+			frames_given = 0;
+		}
+		let frames_due = ((start.elapsed().as_micros() * u128::from(input.sample_rate_hz))
+			/ 1_000_000) as u64
+			- frames_given;
+		frames_given += frames_due;
+		let step = f64::from(data.sample_rate_hz) / f64::from(input.sample_rate_hz);
+		for _ in 0..frames_due {
+			let idx = position as usize;
+			if idx >= num_frames {
+				break;
+			}
+			input.push_frame(&data.samples[idx * channels..(idx + 1) * channels], &MIXER);
+			position += step;
+		}
+	}
+	info!("Audio input file finished");
+}
+
+/// Open the host's default capture device, in the current input format.
+///
+/// Capture starts paused.
+pub fn open_capture(s: &PixState) -> PixResult<AudioDevice<Capture>> {
+	let (format, sample_rate_hz) = {
+		let input = INPUT.lock().unwrap();
+		(input.format, input.sample_rate_hz)
+	};
+	let desired = AudioSpecDesired {
+		freq: Some(sample_rate_hz as i32),
+		channels: Some(channels(format)),
+		samples: Some(SDL_BUFFER_FRAMES),
+	};
+	s.open_capture(None, &desired, |spec| {
+		info!("Opened audio capture: {:?}", spec);
+		Capture {
+			channels: spec.channels.clamp(1, 2),
+		}
+	})
+}
+
+/// Send the audio output to a WAV file instead of the speakers.
+///
+/// In real time, we take samples at the configured sample rate, as a sound
+/// card would. If `fast` is set, we take everything as soon as the OS gives it
+/// to us.
+pub fn start_wav(path: &Path, fast: bool) {
+	info!("Writing audio output to: {}", path.display());
+	*WAV_SINK.lock().unwrap() = Some(WavSink {
+		path: path.to_owned(),
+		fast,
+		writer: None,
+		stopped: false,
+	});
+	if !fast {
+		std::thread::spawn(|| pace_output(true));
+	}
+}
+
+/// Play the audio output to nowhere, when we have no speakers.
+///
+/// Samples are taken at the configured rate, as a sound card would, so the OS
+/// still sees its buffer drain. Does nothing if we're writing a WAV file,
+/// as that takes the samples already.
+pub fn discard_output() {
+	if !playback_enabled() {
+		return;
+	}
+	info!("No speakers, so the audio output is thrown away");
+	std::thread::spawn(|| pace_output(false));
+}
+
+/// Finish the WAV file, if we're writing one.
+pub fn stop_wav() {
+	let sink = WAV_SINK.lock().unwrap().take();
+	if let Some((_, writer)) = sink.and_then(|sink| sink.writer) {
+		if let Err(e) = writer.finish() {
+			warn!("Failed to finish WAV file: {:?}", e);
+		}
+	}
+}
+
+/// Should the audio output go to the speakers?
+pub fn playback_enabled() -> bool {
+	WAV_SINK.lock().unwrap().is_none()
+}
+
+/// Take samples out of the output buffer at the sample rate, and write them
+/// to the WAV file if `to_wav` is set, or throw them away if not.
+///
+/// If the OS doesn't keep up, the samples it missed are simply lost - we
+/// don't write silence into the file. When writing a WAV file, runs until the
+/// WAV sink is stopped; otherwise runs forever.
+fn pace_output(to_wav: bool) {
+	let mut generation = None;
+	let mut start = Instant::now();
+	let mut frames_taken: u64 = 0;
+	loop {
+		std::thread::sleep(WAV_PERIOD);
+		let mut output = OUTPUT.lock().unwrap();
+		if generation != Some(output.generation) {
+			// New sample rate, so start counting again
+			generation = Some(output.generation);
+			start = Instant::now();
+			frames_taken = 0;
+		}
+		let frames_due = ((start.elapsed().as_micros() * u128::from(output.sample_rate_hz))
+			/ 1_000_000) as u64
+			- frames_taken;
+		frames_taken += frames_due;
+		let bytes_due = (frames_due as usize) * frame_bytes(output.format);
+		let count = bytes_due.min(output.buffer.len());
+		let samples: Vec<u8> = output.buffer.drain(0..count).collect();
+		if !to_wav {
+			continue;
+		}
+		let mut sink = WAV_SINK.lock().unwrap();
+		let Some(sink) = sink.as_mut() else {
+			break;
+		};
+		sink.write(&output, &samples);
+	}
+}
+
+/// Open the host's default playback device, in the current output format.
+///
+/// Playback starts paused.
+pub fn open_playback(s: &PixState) -> PixResult<AudioDevice<Playback>> {
+	let (format, sample_rate_hz) = {
+		let output = OUTPUT.lock().unwrap();
+		(output.format, output.sample_rate_hz)
+	};
+	let desired = AudioSpecDesired {
+		freq: Some(sample_rate_hz as i32),
+		channels: Some(channels(format)),
+		samples: Some(SDL_BUFFER_FRAMES),
+	};
+	s.open_playback(None, &desired, |spec| {
+		info!("Opened audio playback: {:?}", spec);
+		Playback { format }
+	})
+}
+
+/// How many channels does this format have?
+pub fn channels(format: SampleFormat) -> u8 {
+	match format {
+		SampleFormat::EightBitMono | SampleFormat::SixteenBitMono => 1,
+		SampleFormat::EightBitStereo | SampleFormat::SixteenBitStereo => 2,
+		_ => unreachable!("parse_config() only accepts formats we know"),
+	}
+}
+
+/// How many bytes does one sample, for one channel, take up?
+pub fn sample_bytes(format: SampleFormat) -> usize {
+	match format {
+		SampleFormat::EightBitMono | SampleFormat::EightBitStereo => 1,
+		SampleFormat::SixteenBitMono | SampleFormat::SixteenBitStereo => 2,
+		_ => unreachable!("parse_config() only accepts formats we know"),
+	}
+}
+
+/// How many bytes does one sample frame (one sample for every channel) take
+/// up?
+pub fn frame_bytes(format: SampleFormat) -> usize {
+	sample_bytes(format) * usize::from(channels(format))
+}
+
+/// Scale a sample by a mixer level, where `max_level` leaves it unchanged.
+fn scale_sample(sample: i16, level: u8, max_level: u8) -> i16 {
+	((i32::from(sample) * i32::from(level)) / i32::from(max_level)) as i16
+}
+
+/// Get the samples to write to the WAV file, scaled by the Line Out level.
+fn wav_samples(samples: &[u8], format: SampleFormat, mixer: &[MixerChannel]) -> Vec<u8> {
+	scale_bytes(samples, format, &mixer[LINE_OUT])
+}
+
+/// Take samples out of a buffer for the speakers, scaled by the Headphones
+/// level.
+fn speaker_samples(
+	buffer: &mut VecDeque<u8>,
+	format: SampleFormat,
+	out: &mut [i16],
+	mixer: &[MixerChannel],
+) {
+	read_samples(buffer, format, out);
+	for sample in out.iter_mut() {
+		*sample = mixer[HEADPHONES].scale(*sample);
+	}
+}
+
+/// Scale some samples, in the given format, by a mixer channel's level.
+fn scale_bytes(samples: &[u8], format: SampleFormat, channel: &MixerChannel) -> Vec<u8> {
+	if sample_bytes(format) == 1 {
+		samples
+			.iter()
+			.map(|byte| (channel.scale(i16::from(*byte as i8) << 8) >> 8) as u8)
+			.collect()
+	} else {
+		samples
+			.chunks_exact(2)
+			.flat_map(|pair| {
+				channel
+					.scale(i16::from_le_bytes([pair[0], pair[1]]))
+					.to_le_bytes()
+			})
+			.collect()
+	}
+}
+
+/// Take samples out of a buffer, converting them to 16-bit.
+///
+/// If the buffer runs dry, the rest of `out` is filled with silence.
+fn read_samples(buffer: &mut VecDeque<u8>, format: SampleFormat, out: &mut [i16]) {
+	for sample in out.iter_mut() {
+		*sample = match sample_bytes(format) {
+			1 => buffer
+				.pop_front()
+				.map(|byte| i16::from(byte as i8) << 8)
+				.unwrap_or(0),
+			_ if buffer.len() >= 2 => {
+				let low = buffer.pop_front().unwrap();
+				let high = buffer.pop_front().unwrap();
+				i16::from_le_bytes([low, high])
+			}
+			_ => 0,
+		};
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Impl Blocks
+// -----------------------------------------------------------------------------
+
+impl Stream {
+	/// Make a new, empty, stream of 16-bit stereo at 48 kHz.
+	const fn new() -> Stream {
+		Stream {
+			format: SampleFormat::SixteenBitStereo,
+			sample_rate_hz: 48000,
+			buffer: VecDeque::new(),
+			generation: 0,
+		}
+	}
+
+	/// Change the sample format and rate, throwing away any buffered samples.
+	fn configure(&mut self, format: SampleFormat, sample_rate_hz: u32) {
+		self.format = format;
+		self.sample_rate_hz = sample_rate_hz;
+		self.buffer.clear();
+		self.generation = self.generation.wrapping_add(1);
+	}
+
+	/// Get the sample format and rate.
+	fn config(&self) -> common::audio::Config {
+		common::audio::Config {
+			sample_format: self.format.into(),
+			sample_rate_hz: self.sample_rate_hz,
+		}
+	}
+
+	/// Add one sample frame (with one or two channels) to the buffer,
+	/// converting it to our format.
+	///
+	/// The samples are scaled by the Line In level. If there isn't room, the
+	/// frame is dropped.
+	fn push_frame(&mut self, frame: &[i16], mixer: &[MixerChannel]) {
+		if self.space() < frame_bytes(self.format) {
+			return;
+		}
+		let left = mixer[LINE_IN].scale(frame[0]);
+		let right = frame
+			.get(1)
+			.map_or(left, |right| mixer[LINE_IN].scale(*right));
+		let mono = ((i32::from(left) + i32::from(right)) / 2) as i16;
+		let samples: &[i16] = match channels(self.format) {
+			1 => &[mono],
+			_ => &[left, right],
+		};
+		for sample in samples {
+			if sample_bytes(self.format) == 1 {
+				self.buffer.push_back((sample >> 8) as u8);
+			} else {
+				self.buffer.extend(sample.to_le_bytes());
+			}
+		}
+	}
+
+	/// Add as many whole sample frames as will fit to the buffer.
+	///
+	/// Returns how many bytes we took.
+	fn write(&mut self, samples: &[u8]) -> usize {
+		let count = samples.len().min(self.space());
+		let count = count - (count % frame_bytes(self.format));
+		self.buffer.extend(&samples[0..count]);
+		count
+	}
+
+	/// How many bytes are free in the buffer?
+	fn space(&self) -> usize {
+		BUFFER_BYTES.saturating_sub(self.buffer.len())
+	}
+}
+
+impl WavSink {
+	/// Write samples from the given stream into the WAV file.
+	///
+	/// Creates the file if this is the first time. WAV files can't change
+	/// format part way through, so we stop if the OS changes the
+	/// configuration.
+	fn write(&mut self, output: &Stream, samples: &[u8]) {
+		if self.stopped || samples.is_empty() {
+			return;
+		}
+		if self.writer.is_none() {
+			match WavWriter::create(&self.path, output.format, output.sample_rate_hz) {
+				Ok(writer) => self.writer = Some((output.generation, writer)),
+				Err(e) => {
+					warn!("Failed to create WAV file: {:?}", e);
+					self.stopped = true;
+					return;
+				}
+			}
+		}
+		let Some((generation, writer)) = self.writer.as_mut() else {
+			return;
+		};
+		if *generation != output.generation {
+			warn!("Audio output configuration changed, so the WAV file stops here");
+			self.stopped = true;
+			return;
+		}
+		let samples = wav_samples(samples, output.format, &MIXER);
+		if let Err(e) = writer.write_samples(&samples) {
+			warn!("Failed to write WAV file: {:?}", e);
+			self.stopped = true;
+		}
+	}
+}
+
+impl MixerChannel {
+	/// Make a new mixer channel, turned all the way up.
+	const fn new(name: &'static str, direction: Direction) -> MixerChannel {
+		MixerChannel {
+			name,
+			direction,
+			max_level: 255,
+			level: AtomicU8::new(255),
+		}
+	}
+
+	/// Scale a sample by this channel's level.
+	fn scale(&self, sample: i16) -> i16 {
+		scale_sample(sample, self.level.load(Ordering::Relaxed), self.max_level)
+	}
+}
+
+impl AudioCallback for Playback {
+	type Channel = i16;
+
+	/// Called by SDL's audio thread when it needs more samples.
+	fn callback(&mut self, out: &mut [i16]) {
+		let mut output = OUTPUT.lock().unwrap();
+		if output.format != self.format {
+			// The OS changed format, and we haven't been re-opened yet
+			out.fill(0);
+			return;
+		}
+		speaker_samples(&mut output.buffer, self.format, out, &MIXER);
+	}
+}
+
+impl AudioCallback for Capture {
+	type Channel = i16;
+
+	/// Called by SDL's audio thread when it has recorded some samples.
+	fn callback(&mut self, samples: &mut [i16]) {
+		let mut input = INPUT.lock().unwrap();
+		for frame in samples.chunks_exact(usize::from(self.channels)) {
+			input.push_frame(frame, &MIXER);
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Make a mixer with the given Line Out, Headphones and Line In levels.
+	fn mixer(line_out: u8, headphones: u8, line_in: u8) -> [MixerChannel; 3] {
+		let mixer = [
+			MixerChannel::new("Line Out", Direction::Output),
+			MixerChannel::new("Headphones", Direction::Output),
+			MixerChannel::new("Line In", Direction::Input),
+		];
+		mixer[LINE_OUT].level.store(line_out, Ordering::Relaxed);
+		mixer[HEADPHONES].level.store(headphones, Ordering::Relaxed);
+		mixer[LINE_IN].level.store(line_in, Ordering::Relaxed);
+		mixer
+	}
+
+	/// Send one 16-bit sample down each of the three paths through the mixer,
+	/// and see what comes out: (WAV file, speakers, input).
+	fn mix(mixer: &[MixerChannel], sample: i16) -> (i16, i16, i16) {
+		let format = SampleFormat::SixteenBitMono;
+		let wav = wav_samples(&sample.to_le_bytes(), format, mixer);
+		let mut buffer: VecDeque<u8> = sample.to_le_bytes().into_iter().collect();
+		let mut speaker = [0i16];
+		speaker_samples(&mut buffer, format, &mut speaker, mixer);
+		let mut input = empty_stream(format);
+		input.push_frame(&[sample], mixer);
+		(
+			i16::from_le_bytes([wav[0], wav[1]]),
+			speaker[0],
+			i16::from_le_bytes([input.buffer[0], input.buffer[1]]),
+		)
+	}
+
+	/// Make an empty stream in the given format.
+	fn empty_stream(format: SampleFormat) -> Stream {
+		let mut stream = Stream::new();
+		stream.configure(format, 48000);
+		stream
+	}
+
+	#[test]
+	fn partial_writes() {
+		let mut stream = empty_stream(SampleFormat::SixteenBitStereo);
+		// Only whole frames of four bytes are taken
+		assert_eq!(stream.write(&[1, 2, 3, 4, 5, 6, 7]), 4);
+		assert_eq!(stream.write(&[8, 9, 10]), 0);
+		assert_eq!(stream.buffer, [1, 2, 3, 4]);
+		let mut stream = empty_stream(SampleFormat::EightBitMono);
+		assert_eq!(stream.write(&[1, 2, 3]), 3);
+	}
+
+	#[test]
+	fn buffer_limit() {
+		let mut stream = empty_stream(SampleFormat::SixteenBitStereo);
+		assert_eq!(stream.write(&vec![0; BUFFER_BYTES + 100]), BUFFER_BYTES);
+		assert_eq!(stream.space(), 0);
+		assert_eq!(stream.write(&[0; 4]), 0);
+		// Reading some makes room for more
+		let mut out = [0i16; 2];
+		read_samples(&mut stream.buffer, stream.format, &mut out);
+		assert_eq!(stream.space(), 4);
+		assert_eq!(stream.write(&[0; 8]), 4);
+	}
+
+	#[test]
+	fn wrap_around() {
+		let mut stream = empty_stream(SampleFormat::SixteenBitMono);
+		let mut out = [0i16; 3];
+		// Go round the buffer a few times, checking nothing gets mixed up
+		for round in 0..(BUFFER_BYTES / 4) {
+			let base = (round * 3) as i16;
+			let samples: Vec<u8> = [base, base + 1, base + 2]
+				.iter()
+				.flat_map(|sample| sample.to_le_bytes())
+				.collect();
+			assert_eq!(stream.write(&samples), 6);
+			read_samples(&mut stream.buffer, stream.format, &mut out);
+			assert_eq!(out, [base, base + 1, base + 2]);
+		}
+		assert!(stream.buffer.is_empty());
+	}
+
+	#[test]
+	fn read_conversion() {
+		// 8-bit samples are signed, and become the top byte
+		let mut buffer: VecDeque<u8> = [0x00, 0x7F, 0x80, 0xFF].into_iter().collect();
+		let mut out = [1i16; 6];
+		read_samples(&mut buffer, SampleFormat::EightBitMono, &mut out);
+		assert_eq!(out, [0, 0x7F00, i16::MIN, -256, 0, 0]);
+		// 16-bit samples are little-endian, and half a sample is silence
+		let mut buffer: VecDeque<u8> = [0x34, 0x12, 0xFF, 0xFF, 0x01].into_iter().collect();
+		let mut out = [1i16; 3];
+		read_samples(&mut buffer, SampleFormat::SixteenBitStereo, &mut out);
+		assert_eq!(out, [0x1234, -1, 0]);
+	}
+
+	#[test]
+	fn input_conversion() {
+		// Stereo to mono takes the average
+		let mut stream = empty_stream(SampleFormat::SixteenBitMono);
+		stream.push_frame(&[1000, 3000], &MIXER);
+		assert_eq!(stream.buffer, 2000i16.to_le_bytes());
+		// Mono to stereo copies the sample to both channels
+		let mut stream = empty_stream(SampleFormat::SixteenBitStereo);
+		stream.push_frame(&[-2], &MIXER);
+		assert_eq!(stream.buffer, [0xFE, 0xFF, 0xFE, 0xFF]);
+		// 16-bit to 8-bit keeps the top byte
+		let mut stream = empty_stream(SampleFormat::EightBitStereo);
+		stream.push_frame(&[0x1234, -0x1234], &MIXER);
+		assert_eq!(stream.buffer, [0x12, 0xED]);
+	}
+
+	#[test]
+	fn scaling() {
+		for sample in [i16::MIN, -1234, -1, 0, 1, 1234, i16::MAX] {
+			// Turned all the way up leaves samples alone
+			assert_eq!(scale_sample(sample, 255, 255), sample);
+			// Turned all the way down is silent
+			assert_eq!(scale_sample(sample, 0, 255), 0);
+		}
+		assert_eq!(scale_sample(1000, 51, 255), 200);
+		assert_eq!(scale_sample(-1000, 51, 255), -200);
+		// 8-bit samples are scaled too
+		let channel = MixerChannel::new("Test", Direction::Output);
+		channel.level.store(128, Ordering::Relaxed);
+		assert_eq!(
+			scale_bytes(&[0x7F, 0x80, 0x00], SampleFormat::EightBitMono, &channel),
+			[0x3F, 0xBF, 0x00]
+		);
+	}
+
+	#[test]
+	fn mixer_channels() {
+		// Each channel only turns down its own path
+		assert_eq!(mix(&mixer(255, 255, 255), 1000), (1000, 1000, 1000));
+		assert_eq!(mix(&mixer(0, 255, 255), 1000), (0, 1000, 1000));
+		assert_eq!(mix(&mixer(255, 0, 255), 1000), (1000, 0, 1000));
+		assert_eq!(mix(&mixer(255, 255, 0), 1000), (1000, 1000, 0));
+		assert_eq!(mix(&mixer(51, 102, 153), -1000), (-200, -400, -600));
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,185 @@
+//! # Control channel for the Neotron Desktop BIOS
+//!
+//! Lets a script (or a person) drive the emulator by writing one command per
+//! line to our standard input.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+use log::warn;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The commands you can send down the control channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+	/// `frame <path>` - render the current frame to a binary PPM file
+	Frame(PathBuf),
+	/// `screenshot <path>` - render the current frame to a PNG file
+	Screenshot(PathBuf),
+	/// `record start <path>` - start recording frames to a Y4M file
+	RecordStart(PathBuf),
+	/// `record stop` - stop recording frames
+	RecordStop,
+	/// `dump text <path>` - write the text on screen to a UTF-8 file
+	DumpText(PathBuf),
+	/// `dump ansi <path>` - write the text on screen, with ANSI colours
+	DumpAnsi(PathBuf),
+	/// `dump palette <path>` - write the palette to a `.gpl`, `.pal` or `.hex` file
+	DumpPalette(PathBuf),
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Start a thread which reads commands from standard input.
+///
+/// Lines which don't parse are logged and ignored. The thread exits when
+/// standard input is closed.
+pub fn spawn_stdin_reader() -> mpsc::Receiver<Command> {
+	let (sender, receiver) = mpsc::channel();
+	std::thread::spawn(move || {
+		for line in std::io::stdin().lines() {
+			let Ok(line) = line else {
+				break;
+			};
+			match parse(&line) {
+				Ok(Some(command)) => {
+					if sender.send(command).is_err() {
+						break;
+					}
+				}
+				Ok(None) => {}
+				Err(e) => warn!("Bad control command {:?}: {}", line, e),
+			}
+		}
+	});
+	receiver
+}
+
+/// Parse a single line from the control channel.
+///
+/// Returns `Ok(None)` for blank lines.
+fn parse(line: &str) -> Result<Option<Command>, &'static str> {
+	let mut words = line.split_whitespace();
+	let Some(name) = words.next() else {
+		return Ok(None);
+	};
+	let command = match name {
+		"frame" => Command::Frame(words.next().ok_or("missing path")?.into()),
+		"screenshot" => Command::Screenshot(words.next().ok_or("missing path")?.into()),
+		"record" => match words.next() {
+			Some("start") => Command::RecordStart(words.next().ok_or("missing path")?.into()),
+			Some("stop") => Command::RecordStop,
+			_ => return Err("expected start or stop"),
+		},
+		"dump" => match words.next() {
+			Some("text") => Command::DumpText(words.next().ok_or("missing path")?.into()),
+			Some("ansi") => Command::DumpAnsi(words.next().ok_or("missing path")?.into()),
+			Some("palette") => Command::DumpPalette(words.next().ok_or("missing path")?.into()),
+			_ => return Err("expected text, ansi or palette"),
+		},
+		_ => return Err("unknown command"),
+	};
+	if words.next().is_some() {
+		return Err("too many arguments");
+	}
+	Ok(Some(command))
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn frames_and_screenshots() {
+		assert_eq!(
+			parse("frame out.ppm"),
+			Ok(Some(Command::Frame("out.ppm".into())))
+		);
+		assert_eq!(
+			parse("  screenshot   /tmp/shot.png  "),
+			Ok(Some(Command::Screenshot("/tmp/shot.png".into())))
+		);
+	}
+
+	#[test]
+	fn recording() {
+		assert_eq!(
+			parse("record start video.y4m"),
+			Ok(Some(Command::RecordStart("video.y4m".into())))
+		);
+		assert_eq!(parse("record stop"), Ok(Some(Command::RecordStop)));
+	}
+
+	#[test]
+	fn dumps() {
+		assert_eq!(
+			parse("dump text screen.txt"),
+			Ok(Some(Command::DumpText("screen.txt".into())))
+		);
+		assert_eq!(
+			parse("dump ansi screen.ans"),
+			Ok(Some(Command::DumpAnsi("screen.ans".into())))
+		);
+		assert_eq!(
+			parse("dump palette colours.gpl"),
+			Ok(Some(Command::DumpPalette("colours.gpl".into())))
+		);
+	}
+
+	#[test]
+	fn blank_lines() {
+		assert_eq!(parse(""), Ok(None));
+		assert_eq!(parse("   \t "), Ok(None));
+	}
+
+	#[test]
+	fn bad_commands() {
+		assert_eq!(parse("reboot"), Err("unknown command"));
+		assert_eq!(parse("FRAME out.ppm"), Err("unknown command"));
+		assert_eq!(parse("frame"), Err("missing path"));
+		assert_eq!(parse("record start"), Err("missing path"));
+		assert_eq!(parse("record pause"), Err("expected start or stop"));
+		assert_eq!(parse("record"), Err("expected start or stop"));
+		assert_eq!(parse("dump"), Err("expected text, ansi or palette"));
+		assert_eq!(parse("dump fonts x"), Err("expected text, ansi or palette"));
+		assert_eq!(parse("dump text"), Err("missing path"));
+		assert_eq!(parse("record stop now"), Err("too many arguments"));
+		assert_eq!(parse("frame a.ppm b.ppm"), Err("too many arguments"));
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
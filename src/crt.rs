@@ -0,0 +1,299 @@
+//! # CRT display effects for the Neotron Desktop BIOS
+//!
+//! Makes a rendered frame look a little more like it's on a CRT monitor, with
+//! dark gaps between the scan-lines, a glow around bright pixels, and a gently
+//! curved screen. Each effect can be turned on or off by itself. This is all
+//! done in software, so it's not fast.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::render::Frame;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Which CRT effects are turned on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Effects {
+	/// Dark gaps between the scan-lines
+	pub scanlines: bool,
+	/// A glow around bright pixels
+	pub glow: bool,
+	/// A curved screen
+	pub curvature: bool,
+}
+
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// The most output pixels we make from each input pixel, in each direction.
+///
+/// Larger window scales are left to the GPU, as this is all done in software.
+const MAX_OVERSAMPLE: usize = 4;
+
+/// How bright the gap between two scan-lines is, compared to the lines
+const SCANLINE_GAP_LEVEL: f32 = 0.6;
+
+/// How much of the blurred image we add back on top, to make things glow
+const BLOOM_LEVEL: f32 = 0.3;
+
+/// How strongly the screen bulges out in the middle
+const CURVATURE: f32 = 0.04;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Apply the CRT effects to a frame, which will be drawn with the given
+/// horizontal and vertical window scale.
+///
+/// The result is oversampled to roughly match the window scale (up to
+/// `MAX_OVERSAMPLE`), so each scan-line and the gap below it line up with
+/// whole window pixels. With scan-lines on, we always make at least two
+/// output lines per input line, so there's room for the gap.
+pub fn apply(frame: &Frame, scale: (f32, f32), effects: Effects) -> Frame {
+	let glow = effects.glow.then(|| blur(frame));
+	let oversample_x = oversample(scale.0, 1);
+	let oversample_y = oversample(scale.1, if effects.scanlines { 2 } else { 1 });
+	let width = frame.width * oversample_x;
+	let height = frame.height * oversample_y;
+	let mut pixels = vec![0u8; width * height * 3];
+	for out_y in 0..height {
+		// Map to -1.0..1.0, with 0.0 in the middle of the screen
+		let v = (((out_y as f32 + 0.5) / height as f32) * 2.0) - 1.0;
+		for out_x in 0..width {
+			let u = (((out_x as f32 + 0.5) / width as f32) * 2.0) - 1.0;
+			let (curved_u, curved_v) = if effects.curvature {
+				curve(u, v)
+			} else {
+				(u, v)
+			};
+			if !(-1.0..1.0).contains(&curved_u) || !(-1.0..1.0).contains(&curved_v) {
+				// Off the edge of the tube
+				continue;
+			}
+			let src_x = ((curved_u + 1.0) * 0.5) * frame.width as f32;
+			let src_y = ((curved_v + 1.0) * 0.5) * frame.height as f32;
+			let level = if effects.scanlines {
+				scanline_level(src_y, oversample_y)
+			} else {
+				1.0
+			};
+			let src_idx = ((src_y as usize * frame.width) + src_x as usize) * 3;
+			let out_idx = ((out_y * width) + out_x) * 3;
+			for channel in 0..3 {
+				let base = f32::from(frame.pixels[src_idx + channel]);
+				let bloom = glow
+					.as_ref()
+					.map_or(0.0, |glow| glow[src_idx + channel] * BLOOM_LEVEL);
+				pixels[out_idx + channel] = ((base + bloom) * level).min(255.0) as u8;
+			}
+		}
+	}
+	Frame {
+		width,
+		height,
+		pixels,
+	}
+}
+
+/// How many output pixels to make for each input pixel, for a window scale.
+fn oversample(scale: f32, min: usize) -> usize {
+	(scale.round() as usize).clamp(min, MAX_OVERSAMPLE)
+}
+
+/// Barrel distortion - the further from the middle we are, the further out
+/// we sample from.
+///
+/// Both coordinates run from -1.0 to 1.0, with 0.0 in the middle of the
+/// screen.
+fn curve(u: f32, v: f32) -> (f32, f32) {
+	(
+		u * (1.0 + (CURVATURE * v * v)),
+		v * (1.0 + (CURVATURE * u * u)),
+	)
+}
+
+/// How bright an output line is, given where it samples from in the input
+/// (in input lines), and how many output lines each input line makes.
+///
+/// The bottom half of each scan-line (rounded down, but at least one output
+/// line) is the gap between lines.
+fn scanline_level(src_y: f32, oversample_y: usize) -> f32 {
+	let first_gap_row = oversample_y - (oversample_y / 2);
+	let row_in_line = (src_y.fract() * oversample_y as f32) as usize;
+	if row_in_line >= first_gap_row {
+		SCANLINE_GAP_LEVEL
+	} else {
+		1.0
+	}
+}
+
+/// Blur a frame with a 3x3 box filter, to approximate phosphor glow.
+fn blur(frame: &Frame) -> Vec<f32> {
+	let (width, height) = (frame.width, frame.height);
+	// Blur horizontally, then vertically
+	let mut horizontal = vec![0.0f32; width * height * 3];
+	for y in 0..height {
+		for x in 0..width {
+			for channel in 0..3 {
+				let mut total = 0.0;
+				for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+					total += f32::from(frame.pixels[(((y * width) + nx) * 3) + channel]);
+				}
+				horizontal[(((y * width) + x) * 3) + channel] = total / 3.0;
+			}
+		}
+	}
+	let mut result = vec![0.0f32; width * height * 3];
+	for y in 0..height {
+		for x in 0..width {
+			for channel in 0..3 {
+				let mut total = 0.0;
+				for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+					total += horizontal[(((ny * width) + x) * 3) + channel];
+				}
+				result[(((y * width) + x) * 3) + channel] = total / 3.0;
+			}
+		}
+	}
+	result
+}
+
+// -----------------------------------------------------------------------------
+// Impl Blocks
+// -----------------------------------------------------------------------------
+
+impl std::str::FromStr for Effects {
+	type Err = String;
+
+	/// Parse a comma-separated list of effects, like `scanlines,glow`.
+	fn from_str(s: &str) -> Result<Effects, String> {
+		let mut effects = Effects {
+			scanlines: false,
+			glow: false,
+			curvature: false,
+		};
+		for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+			match name.to_ascii_lowercase().as_str() {
+				"scanlines" => effects.scanlines = true,
+				"glow" => effects.glow = true,
+				"curvature" => effects.curvature = true,
+				_ => {
+					return Err(format!(
+						"{:?} is not 'scanlines', 'glow' or 'curvature'",
+						name
+					))
+				}
+			}
+		}
+		Ok(effects)
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn scanlines() {
+		// Two output lines per input line - one lit, one gap
+		assert_eq!(scanline_level(3.0, 2), 1.0);
+		assert_eq!(scanline_level(3.4, 2), 1.0);
+		assert_eq!(scanline_level(3.6, 2), SCANLINE_GAP_LEVEL);
+		// Three output lines - the gap is the last one
+		assert_eq!(scanline_level(0.5, 3), 1.0);
+		assert_eq!(scanline_level(0.7, 3), SCANLINE_GAP_LEVEL);
+		// Four output lines - the gap is the bottom half
+		assert_eq!(scanline_level(10.45, 4), 1.0);
+		assert_eq!(scanline_level(10.55, 4), SCANLINE_GAP_LEVEL);
+	}
+
+	#[test]
+	fn curvature() {
+		// The middle doesn't move
+		assert_eq!(curve(0.0, 0.0), (0.0, 0.0));
+		// Nor do the middles of the edges
+		assert_eq!(curve(1.0, 0.0), (1.0, 0.0));
+		assert_eq!(curve(0.0, -1.0), (0.0, -1.0));
+		// Along the edges, we sample from further out
+		let (u, v) = curve(1.0, 0.5);
+		assert!(u > 1.0);
+		assert!(v > 0.5);
+		// So the corners are off the edge of the tube
+		let (u, v) = curve(-1.0, 1.0);
+		assert!(u < -1.0);
+		assert!(v > 1.0);
+	}
+
+	#[test]
+	fn effects_off() {
+		let frame = Frame {
+			width: 2,
+			height: 1,
+			pixels: vec![10, 20, 30, 200, 100, 50],
+		};
+		let none: Effects = "".parse().unwrap();
+		let result = apply(&frame, (1.0, 1.0), none);
+		assert_eq!((result.width, result.height), (2, 1));
+		assert_eq!(result.pixels, frame.pixels);
+		// Scan-lines need at least two output lines per input line
+		let scanlines: Effects = "scanlines".parse().unwrap();
+		let result = apply(&frame, (1.0, 1.0), scanlines);
+		assert_eq!((result.width, result.height), (2, 2));
+		assert_eq!(&result.pixels[0..6], &frame.pixels);
+		assert_eq!(&result.pixels[6..9], &[6, 12, 18]);
+	}
+
+	#[test]
+	fn parse_effects() {
+		assert_eq!(
+			"scanlines,glow,curvature".parse::<Effects>(),
+			Ok(Effects {
+				scanlines: true,
+				glow: true,
+				curvature: true,
+			})
+		);
+		assert_eq!(
+			"Glow".parse::<Effects>(),
+			Ok(Effects {
+				scanlines: false,
+				glow: true,
+				curvature: false,
+			})
+		);
+		assert!("sparkles".parse::<Effects>().is_err());
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
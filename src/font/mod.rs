@@ -26,12 +26,14 @@
 
 pub mod font16;
 pub mod font8;
+mod psf;
 
 // -----------------------------------------------------------------------------
 // Imports
 // -----------------------------------------------------------------------------
 
-// None
+use std::path::Path;
+use std::sync::OnceLock;
 
 // -----------------------------------------------------------------------------
 // Types
@@ -44,11 +46,70 @@ pub struct Font<'a> {
 	pub data: &'a [u8],
 }
 
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// A font loaded from disk to use instead of `font16::FONT`
+static CUSTOM_FONT16: OnceLock<Font<'static>> = OnceLock::new();
+
+/// A font loaded from disk to use instead of `font8::FONT`
+static CUSTOM_FONT8: OnceLock<Font<'static>> = OnceLock::new();
+
 // -----------------------------------------------------------------------------
 // Functions
 // -----------------------------------------------------------------------------
 
-// None
+/// Get the font for the 8x16 text modes.
+pub fn active_font16() -> &'static Font<'static> {
+	CUSTOM_FONT16.get().unwrap_or(&font16::FONT)
+}
+
+/// Get the font for the 8x8 text modes.
+pub fn active_font8() -> &'static Font<'static> {
+	CUSTOM_FONT8.get().unwrap_or(&font8::FONT)
+}
+
+/// Replace the font for the 8x16 text modes with one loaded from a file.
+///
+/// Can only be called once, at start-up.
+pub fn load_font16(path: &Path) -> std::io::Result<()> {
+	let font = load(path, 16)?;
+	CUSTOM_FONT16
+		.set(font)
+		.map_err(|_| std::io::Error::other("8x16 font already loaded"))
+}
+
+/// Replace the font for the 8x8 text modes with one loaded from a file.
+///
+/// Can only be called once, at start-up.
+pub fn load_font8(path: &Path) -> std::io::Result<()> {
+	let font = load(path, 8)?;
+	CUSTOM_FONT8
+		.set(font)
+		.map_err(|_| std::io::Error::other("8x8 font already loaded"))
+}
+
+/// Load a PSF1, PSF2 or raw font file, which must have the given height.
+///
+/// Fonts live for the life of the program, so we leak the memory.
+fn load(path: &Path, height: usize) -> std::io::Result<Font<'static>> {
+	let contents = std::fs::read(path)?;
+	let (file_height, data) = psf::parse(&contents)
+		.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+	if file_height != height {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!("font is 8x{}, but we need 8x{}", file_height, height),
+		));
+	}
+	let name = path.display().to_string();
+	Ok(Font {
+		name: Box::leak(name.into_boxed_str()),
+		height,
+		data: Box::leak(data.into_boxed_slice()),
+	})
+}
 
 // -----------------------------------------------------------------------------
 // End of file
@@ -0,0 +1,237 @@
+//! # Font file loading for the Neotron Desktop BIOS
+//!
+//! Understands PC Screen Font files (both PSF1 and PSF2), and raw binary
+//! fonts which are just 256 glyphs of 8xN pixels, one byte per row.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+// None
+
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// Every PSF1 file starts with these bytes
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+
+/// Set in the PSF1 mode byte if the file has 512 glyphs instead of 256
+const PSF1_MODE_512: u8 = 0x01;
+
+/// Every PSF2 file starts with these bytes
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+
+/// The size of a PSF2 header (the file tells us, but it can't be smaller)
+const PSF2_HEADER_LEN: usize = 32;
+
+/// How many glyphs we use from a font
+pub const NUM_GLYPHS: usize = 256;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Parse a font file.
+///
+/// Returns the height of each glyph and the glyph data for the first 256
+/// glyphs, one byte per row. Files with fewer glyphs are padded with blank
+/// ones. Only fonts that are 8 pixels wide are supported.
+pub fn parse(data: &[u8]) -> Result<(usize, Vec<u8>), &'static str> {
+	let (height, num_glyphs, glyphs) = if data.starts_with(&PSF1_MAGIC) {
+		parse_psf1(data)?
+	} else if data.starts_with(&PSF2_MAGIC) {
+		parse_psf2(data)?
+	} else {
+		parse_raw(data)?
+	};
+	let wanted = height
+		.checked_mul(num_glyphs.min(NUM_GLYPHS))
+		.ok_or("font is too big")?;
+	let mut result = glyphs.get(0..wanted).ok_or("file is truncated")?.to_vec();
+	result.resize(height * NUM_GLYPHS, 0);
+	Ok((height, result))
+}
+
+/// Parse a PSF1 file, returning the height, glyph count and glyph data.
+fn parse_psf1(data: &[u8]) -> Result<(usize, usize, &[u8]), &'static str> {
+	let header = data.get(0..4).ok_or("PSF1 header is truncated")?;
+	let num_glyphs = if (header[2] & PSF1_MODE_512) != 0 {
+		512
+	} else {
+		256
+	};
+	let height = usize::from(header[3]);
+	if height == 0 {
+		return Err("PSF1 font has zero height");
+	}
+	Ok((height, num_glyphs, &data[4..]))
+}
+
+/// Parse a PSF2 file, returning the height, glyph count and glyph data.
+fn parse_psf2(data: &[u8]) -> Result<(usize, usize, &[u8]), &'static str> {
+	if data.len() < PSF2_HEADER_LEN {
+		return Err("PSF2 header is truncated");
+	}
+	let field = |idx: usize| {
+		let offset = idx * 4;
+		u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize
+	};
+	let header_len = field(2);
+	let num_glyphs = field(4);
+	let bytes_per_glyph = field(5);
+	let height = field(6);
+	let width = field(7);
+	if width != 8 {
+		return Err("only 8 pixel wide fonts are supported");
+	}
+	if height == 0 || bytes_per_glyph != height {
+		return Err("PSF2 glyph size doesn't match its height");
+	}
+	if num_glyphs == 0 {
+		return Err("PSF2 font has no glyphs");
+	}
+	let glyphs = data
+		.get(header_len.max(PSF2_HEADER_LEN)..)
+		.ok_or("PSF2 header is truncated")?;
+	// Check the header against the file before we believe it
+	let glyphs_len = num_glyphs
+		.checked_mul(bytes_per_glyph)
+		.ok_or("PSF2 font is too big")?;
+	if glyphs.len() < glyphs_len {
+		return Err("PSF2 file is truncated");
+	}
+	Ok((height, num_glyphs, glyphs))
+}
+
+/// Parse a raw font, which must be exactly 256 glyphs of 8xN pixels.
+fn parse_raw(data: &[u8]) -> Result<(usize, usize, &[u8]), &'static str> {
+	if data.is_empty() || (data.len() & (NUM_GLYPHS - 1)) != 0 {
+		return Err("raw fonts must be 256 glyphs, one byte per row");
+	}
+	Ok((data.len() / NUM_GLYPHS, NUM_GLYPHS, data))
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Make a PSF2 header with the given glyph count, glyph size, height and
+	/// width.
+	fn psf2_header(num_glyphs: u32, bytes_per_glyph: u32, height: u32, width: u32) -> Vec<u8> {
+		let mut data = PSF2_MAGIC.to_vec();
+		for field in [
+			0,
+			PSF2_HEADER_LEN as u32,
+			0,
+			num_glyphs,
+			bytes_per_glyph,
+			height,
+			width,
+		] {
+			data.extend_from_slice(&field.to_le_bytes());
+		}
+		data
+	}
+
+	#[test]
+	fn psf1() {
+		let mut data = vec![0x36, 0x04, 0x00, 8];
+		data.extend((0..256 * 8).map(|x| x as u8));
+		let (height, num_glyphs, glyphs) = parse_psf1(&data).unwrap();
+		assert_eq!((height, num_glyphs, glyphs.len()), (8, 256, 256 * 8));
+		assert_eq!(glyphs[9], 9);
+	}
+
+	#[test]
+	fn psf1_512_glyphs() {
+		let data = [0x36, 0x04, PSF1_MODE_512, 16];
+		assert_eq!(parse_psf1(&data).unwrap().1, 512);
+	}
+
+	#[test]
+	fn psf1_bad_header() {
+		assert!(parse_psf1(&[0x36, 0x04, 0x00]).is_err());
+		assert!(parse_psf1(&[0x36, 0x04, 0x00, 0]).is_err());
+	}
+
+	#[test]
+	fn psf2() {
+		let mut data = psf2_header(2, 16, 16, 8);
+		data.extend([0xAA; 32]);
+		let (height, num_glyphs, glyphs) = parse_psf2(&data).unwrap();
+		assert_eq!((height, num_glyphs, glyphs.len()), (16, 2, 32));
+	}
+
+	#[test]
+	fn psf2_is_padded_to_256_glyphs() {
+		let mut data = psf2_header(2, 16, 16, 8);
+		data.extend([0xAA; 32]);
+		let (height, glyphs) = parse(&data).unwrap();
+		assert_eq!(height, 16);
+		assert_eq!(glyphs.len(), 16 * NUM_GLYPHS);
+		assert_eq!(glyphs[31], 0xAA);
+		assert_eq!(glyphs[32], 0x00);
+	}
+
+	#[test]
+	fn psf2_bad_headers() {
+		// Too wide
+		assert!(parse_psf2(&psf2_header(1, 16, 16, 9)).is_err());
+		// Glyph size doesn't match the height
+		assert!(parse_psf2(&psf2_header(1, 8, 16, 8)).is_err());
+		// No glyphs
+		assert!(parse_psf2(&psf2_header(0, 16, 16, 8)).is_err());
+		// Header is cut short
+		assert!(parse_psf2(&psf2_header(1, 16, 16, 8)[0..20]).is_err());
+	}
+
+	#[test]
+	fn psf2_truncated() {
+		let mut data = psf2_header(4, 16, 16, 8);
+		data.extend([0xAA; 48]);
+		assert!(parse_psf2(&data).is_err());
+	}
+
+	#[test]
+	fn psf2_huge_glyph_count() {
+		let mut data = psf2_header(u32::MAX, 16, 16, 8);
+		data.extend([0xAA; 16]);
+		assert!(parse_psf2(&data).is_err());
+		assert!(parse(&data).is_err());
+	}
+
+	#[test]
+	fn raw() {
+		let (height, glyphs) = parse(&[0x55; 256 * 14]).unwrap();
+		assert_eq!((height, glyphs.len()), (14, 256 * 14));
+		assert!(parse(&[0x55; 100]).is_err());
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,352 @@
+//! # Game controller support for the Neotron Desktop BIOS
+//!
+//! The BIOS HID API only knows about keyboards and mice, so we turn game
+//! controllers into key presses - the D-pad and left stick become the arrow
+//! keys, and the buttons become the keys games usually use for fire and
+//! jump. Every controller types the same keys.
+//!
+//! Several controls can hold the same key down (the D-pad and the stick both
+//! press the arrow keys, and so does every other controller), so we count how
+//! many are holding each key, and only release it when they all let go.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use pix_engine::prelude::{Axis, ControllerButton};
+
+use crate::common::hid::KeyCode;
+use crate::AppEvent;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Tracks what every controller is holding down, so we know when a stick
+/// crosses the dead zone, and what to let go of when one is unplugged.
+#[derive(Debug, Default)]
+pub struct Gamepads {
+	/// Each controller we've heard from, by its SDL ID
+	controllers: Vec<(u32, Controller)>,
+	/// How many controls are holding each key down
+	held_keys: Vec<(KeyCode, u32)>,
+}
+
+/// What one controller is holding down.
+#[derive(Debug, Default)]
+struct Controller {
+	/// Which way the left stick is pushed
+	stick: Stick,
+	/// Which of our buttons are held down
+	buttons: Vec<ControllerButton>,
+}
+
+/// Which way a stick is pushed, on each axis
+#[derive(Debug, Default, Clone, Copy)]
+struct Stick {
+	/// -1 for left, 0 for centre, 1 for right
+	x: i8,
+	/// -1 for up, 0 for centre, 1 for down
+	y: i8,
+}
+
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// The key each controller button presses.
+static BUTTONS: &[(ControllerButton, KeyCode)] = &[
+	(ControllerButton::DPadUp, KeyCode::ArrowUp),
+	(ControllerButton::DPadDown, KeyCode::ArrowDown),
+	(ControllerButton::DPadLeft, KeyCode::ArrowLeft),
+	(ControllerButton::DPadRight, KeyCode::ArrowRight),
+	(ControllerButton::A, KeyCode::Spacebar),
+	(ControllerButton::B, KeyCode::LControl),
+	(ControllerButton::X, KeyCode::LAlt),
+	(ControllerButton::Y, KeyCode::LShift),
+	(ControllerButton::Start, KeyCode::Return),
+	(ControllerButton::Back, KeyCode::Escape),
+];
+
+/// The keys for pushing a stick left and right.
+const X_KEYS: (KeyCode, KeyCode) = (KeyCode::ArrowLeft, KeyCode::ArrowRight);
+
+/// The keys for pushing a stick up and down.
+const Y_KEYS: (KeyCode, KeyCode) = (KeyCode::ArrowUp, KeyCode::ArrowDown);
+
+/// How far (out of 32767) a stick must move before we count it as pushed.
+const DEAD_ZONE: i16 = 16384;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Get the key for a controller button, if it has one.
+fn button_key(button: ControllerButton) -> Option<KeyCode> {
+	BUTTONS
+		.iter()
+		.find(|(table_button, _)| *table_button == button)
+		.map(|(_, key)| *key)
+}
+
+/// Which way is an axis pushed, taking the dead zone into account?
+fn direction(value: i16) -> i8 {
+	if value < -DEAD_ZONE {
+		-1
+	} else if value > DEAD_ZONE {
+		1
+	} else {
+		0
+	}
+}
+
+/// The key for pushing a stick axis in the given direction, if it's pushed.
+fn direction_key(dir: i8, keys: (KeyCode, KeyCode)) -> Option<KeyCode> {
+	match dir {
+		0 => None,
+		dir if dir < 0 => Some(keys.0),
+		_ => Some(keys.1),
+	}
+}
+
+/// Find the state for a controller, adding it if it's new.
+fn controller_mut(controllers: &mut Vec<(u32, Controller)>, controller_id: u32) -> &mut Controller {
+	let idx = match controllers.iter().position(|(id, _)| *id == controller_id) {
+		Some(idx) => idx,
+		None => {
+			controllers.push((controller_id, Controller::default()));
+			controllers.len() - 1
+		}
+	};
+	&mut controllers[idx].1
+}
+
+// -----------------------------------------------------------------------------
+// Impl Blocks
+// -----------------------------------------------------------------------------
+
+impl Gamepads {
+	/// Handle a controller button being pressed or released.
+	///
+	/// Returns the key events to send to the OS, if any.
+	pub fn button(
+		&mut self,
+		controller_id: u32,
+		button: ControllerButton,
+		pressed: bool,
+	) -> Vec<AppEvent> {
+		let mut events = Vec::new();
+		let Some(key) = button_key(button) else {
+			return events;
+		};
+		let buttons = &mut controller_mut(&mut self.controllers, controller_id).buttons;
+		let held = buttons.iter().position(|held| *held == button);
+		match (pressed, held) {
+			(true, None) => {
+				buttons.push(button);
+				self.press(&mut events, key);
+			}
+			(false, Some(idx)) => {
+				buttons.remove(idx);
+				self.release(&mut events, key);
+			}
+			_ => {}
+		}
+		events
+	}
+
+	/// Handle a controller's axis moving.
+	///
+	/// Returns the key events to send to the OS, if any.
+	pub fn axis_motion(&mut self, controller_id: u32, axis: Axis, value: i16) -> Vec<AppEvent> {
+		let stick = &mut controller_mut(&mut self.controllers, controller_id).stick;
+		let new = direction(value);
+		let (old, keys) = match axis {
+			Axis::LeftX => (std::mem::replace(&mut stick.x, new), X_KEYS),
+			Axis::LeftY => (std::mem::replace(&mut stick.y, new), Y_KEYS),
+			_ => return Vec::new(),
+		};
+		let mut events = Vec::new();
+		if old != new {
+			if let Some(key) = direction_key(old, keys) {
+				self.release(&mut events, key);
+			}
+			if let Some(key) = direction_key(new, keys) {
+				self.press(&mut events, key);
+			}
+		}
+		events
+	}
+
+	/// Handle a controller being unplugged.
+	///
+	/// Returns key releases for everything it was holding down.
+	pub fn removed(&mut self, controller_id: u32) -> Vec<AppEvent> {
+		let mut events = Vec::new();
+		let Some(idx) = self
+			.controllers
+			.iter()
+			.position(|(id, _)| *id == controller_id)
+		else {
+			return events;
+		};
+		let (_, controller) = self.controllers.remove(idx);
+		for button in controller.buttons {
+			if let Some(key) = button_key(button) {
+				self.release(&mut events, key);
+			}
+		}
+		let stick = controller.stick;
+		for key in [
+			direction_key(stick.x, X_KEYS),
+			direction_key(stick.y, Y_KEYS),
+		]
+		.into_iter()
+		.flatten()
+		{
+			self.release(&mut events, key);
+		}
+		events
+	}
+
+	/// Hold a key down, pressing it if nothing else already is.
+	fn press(&mut self, events: &mut Vec<AppEvent>, key: KeyCode) {
+		match self.held_keys.iter_mut().find(|(held, _)| *held == key) {
+			Some((_, count)) => *count += 1,
+			None => {
+				self.held_keys.push((key, 1));
+				events.push(AppEvent::KeyDown(key));
+			}
+		}
+	}
+
+	/// Stop holding a key down, releasing it if nothing else is holding it.
+	fn release(&mut self, events: &mut Vec<AppEvent>, key: KeyCode) {
+		let Some(idx) = self.held_keys.iter().position(|(held, _)| *held == key) else {
+			return;
+		};
+		self.held_keys[idx].1 -= 1;
+		if self.held_keys[idx].1 == 0 {
+			self.held_keys.remove(idx);
+			events.push(AppEvent::KeyUp(key));
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn buttons() {
+		let mut pads = Gamepads::default();
+		assert_eq!(
+			pads.button(0, ControllerButton::A, true),
+			[AppEvent::KeyDown(KeyCode::Spacebar)]
+		);
+		assert_eq!(
+			pads.button(0, ControllerButton::A, false),
+			[AppEvent::KeyUp(KeyCode::Spacebar)]
+		);
+		// Unmapped buttons, and releases we never saw pressed, do nothing
+		assert_eq!(pads.button(0, ControllerButton::Guide, true), []);
+		assert_eq!(pads.button(0, ControllerButton::B, false), []);
+	}
+
+	#[test]
+	fn stick() {
+		let mut pads = Gamepads::default();
+		assert_eq!(pads.axis_motion(0, Axis::LeftX, 1000), []);
+		assert_eq!(
+			pads.axis_motion(0, Axis::LeftX, -30000),
+			[AppEvent::KeyDown(KeyCode::ArrowLeft)]
+		);
+		assert_eq!(
+			pads.axis_motion(0, Axis::LeftX, 30000),
+			[
+				AppEvent::KeyUp(KeyCode::ArrowLeft),
+				AppEvent::KeyDown(KeyCode::ArrowRight)
+			]
+		);
+		assert_eq!(
+			pads.axis_motion(0, Axis::LeftX, 0),
+			[AppEvent::KeyUp(KeyCode::ArrowRight)]
+		);
+	}
+
+	#[test]
+	fn dpad_and_stick_share_arrows() {
+		let mut pads = Gamepads::default();
+		assert_eq!(
+			pads.button(0, ControllerButton::DPadUp, true),
+			[AppEvent::KeyDown(KeyCode::ArrowUp)]
+		);
+		assert_eq!(pads.axis_motion(0, Axis::LeftY, -30000), []);
+		// Still held by the stick
+		assert_eq!(pads.button(0, ControllerButton::DPadUp, false), []);
+		assert_eq!(
+			pads.axis_motion(0, Axis::LeftY, 0),
+			[AppEvent::KeyUp(KeyCode::ArrowUp)]
+		);
+	}
+
+	#[test]
+	fn controllers_share_keys() {
+		let mut pads = Gamepads::default();
+		assert_eq!(
+			pads.button(0, ControllerButton::Start, true),
+			[AppEvent::KeyDown(KeyCode::Return)]
+		);
+		assert_eq!(pads.button(1, ControllerButton::Start, true), []);
+		assert_eq!(pads.button(0, ControllerButton::Start, false), []);
+		assert_eq!(
+			pads.button(1, ControllerButton::Start, false),
+			[AppEvent::KeyUp(KeyCode::Return)]
+		);
+	}
+
+	#[test]
+	fn unplugging_releases_everything() {
+		let mut pads = Gamepads::default();
+		pads.button(3, ControllerButton::A, true);
+		pads.button(3, ControllerButton::DPadLeft, true);
+		pads.axis_motion(3, Axis::LeftX, -30000);
+		pads.axis_motion(3, Axis::LeftY, 30000);
+		assert_eq!(
+			pads.removed(3),
+			[
+				AppEvent::KeyUp(KeyCode::Spacebar),
+				AppEvent::KeyUp(KeyCode::ArrowLeft),
+				AppEvent::KeyUp(KeyCode::ArrowDown),
+			]
+		);
+		assert_eq!(pads.removed(3), []);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
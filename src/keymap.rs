@@ -0,0 +1,689 @@
+//! # Keyboard mapping for the Neotron Desktop BIOS
+//!
+//! Converts host keys, and host characters, into the Neotron key presses that
+//! would type them.
+//!
+//! The Neotron's `KeyCode`s say where a key is on the keyboard, not what is
+//! printed on it - the OS applies its own keyboard layout. pix-engine only
+//! tells us what is printed on a key, so we watch SDL's raw keyboard events
+//! for the physical scancode, and convert that instead.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use log::debug;
+use pix_engine::prelude::Key;
+use sdl2::keyboard::{Keycode, Scancode};
+
+use crate::common::hid::KeyCode;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// A key event as SDL reported it, before pix-engine threw the scancode away.
+#[derive(Debug, Clone, Copy)]
+struct RawKey {
+	/// Which physical key it was, if SDL knows
+	scancode: Option<Scancode>,
+	/// The key pix-engine will report for it, if any
+	key: Option<Key>,
+	/// Was it pressed (or released)?
+	pressed: bool,
+}
+
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// Which key types each printable ASCII character on a US keyboard.
+///
+/// The `bool` says whether Shift must be held down.
+static US_CHARS: &[(char, KeyCode, bool)] = &[
+	(' ', KeyCode::Spacebar, false),
+	('!', KeyCode::Key1, true),
+	('"', KeyCode::Oem3, true),
+	('#', KeyCode::Key3, true),
+	('$', KeyCode::Key4, true),
+	('%', KeyCode::Key5, true),
+	('&', KeyCode::Key7, true),
+	('\'', KeyCode::Oem3, false),
+	('(', KeyCode::Key9, true),
+	(')', KeyCode::Key0, true),
+	('*', KeyCode::Key8, true),
+	('+', KeyCode::OemPlus, true),
+	(',', KeyCode::OemComma, false),
+	('-', KeyCode::OemMinus, false),
+	('.', KeyCode::OemPeriod, false),
+	('/', KeyCode::Oem2, false),
+	('0', KeyCode::Key0, false),
+	('1', KeyCode::Key1, false),
+	('2', KeyCode::Key2, false),
+	('3', KeyCode::Key3, false),
+	('4', KeyCode::Key4, false),
+	('5', KeyCode::Key5, false),
+	('6', KeyCode::Key6, false),
+	('7', KeyCode::Key7, false),
+	('8', KeyCode::Key8, false),
+	('9', KeyCode::Key9, false),
+	(':', KeyCode::Oem1, true),
+	(';', KeyCode::Oem1, false),
+	('<', KeyCode::OemComma, true),
+	('=', KeyCode::OemPlus, false),
+	('>', KeyCode::OemPeriod, true),
+	('?', KeyCode::Oem2, true),
+	('@', KeyCode::Key2, true),
+	('[', KeyCode::Oem4, false),
+	('\\', KeyCode::Oem5, false),
+	(']', KeyCode::Oem6, false),
+	('^', KeyCode::Key6, true),
+	('_', KeyCode::OemMinus, true),
+	('`', KeyCode::Oem8, false),
+	('{', KeyCode::Oem4, true),
+	('|', KeyCode::Oem5, true),
+	('}', KeyCode::Oem6, true),
+	('~', KeyCode::Oem8, true),
+];
+
+/// Key events SDL has seen, which pix-engine hasn't given us yet.
+static RAW_KEYS: Mutex<VecDeque<RawKey>> = Mutex::new(VecDeque::new());
+
+/// How many key events we keep in `RAW_KEYS`.
+///
+/// pix-engine gives us its events long before this many can pile up, so any
+/// more must be ones it has swallowed.
+const MAX_RAW_KEYS: usize = 32;
+
+/// Which Neotron key is in the same place on the keyboard as each SDL
+/// scancode.
+///
+/// On an ISO keyboard, the key left of Enter (`#~` on a UK layout) is
+/// `NonUsHash` and the key left of Z (`\|` on a UK layout) is
+/// `NonUsBackslash`. These are `Oem7` and `Oem5`, as pc-keyboard has them.
+/// Some host OSes report the key left of Enter as `Backslash` instead, which
+/// we can't tell apart from the US `\|` key.
+static SCANCODES: &[(Scancode, KeyCode)] = &[
+	(Scancode::A, KeyCode::A),
+	(Scancode::B, KeyCode::B),
+	(Scancode::C, KeyCode::C),
+	(Scancode::D, KeyCode::D),
+	(Scancode::E, KeyCode::E),
+	(Scancode::F, KeyCode::F),
+	(Scancode::G, KeyCode::G),
+	(Scancode::H, KeyCode::H),
+	(Scancode::I, KeyCode::I),
+	(Scancode::J, KeyCode::J),
+	(Scancode::K, KeyCode::K),
+	(Scancode::L, KeyCode::L),
+	(Scancode::M, KeyCode::M),
+	(Scancode::N, KeyCode::N),
+	(Scancode::O, KeyCode::O),
+	(Scancode::P, KeyCode::P),
+	(Scancode::Q, KeyCode::Q),
+	(Scancode::R, KeyCode::R),
+	(Scancode::S, KeyCode::S),
+	(Scancode::T, KeyCode::T),
+	(Scancode::U, KeyCode::U),
+	(Scancode::V, KeyCode::V),
+	(Scancode::W, KeyCode::W),
+	(Scancode::X, KeyCode::X),
+	(Scancode::Y, KeyCode::Y),
+	(Scancode::Z, KeyCode::Z),
+	(Scancode::Num1, KeyCode::Key1),
+	(Scancode::Num2, KeyCode::Key2),
+	(Scancode::Num3, KeyCode::Key3),
+	(Scancode::Num4, KeyCode::Key4),
+	(Scancode::Num5, KeyCode::Key5),
+	(Scancode::Num6, KeyCode::Key6),
+	(Scancode::Num7, KeyCode::Key7),
+	(Scancode::Num8, KeyCode::Key8),
+	(Scancode::Num9, KeyCode::Key9),
+	(Scancode::Num0, KeyCode::Key0),
+	(Scancode::Return, KeyCode::Return),
+	(Scancode::Escape, KeyCode::Escape),
+	(Scancode::Backspace, KeyCode::Backspace),
+	(Scancode::Tab, KeyCode::Tab),
+	(Scancode::Space, KeyCode::Spacebar),
+	(Scancode::Minus, KeyCode::OemMinus),
+	(Scancode::Equals, KeyCode::OemPlus),
+	(Scancode::LeftBracket, KeyCode::Oem4),
+	(Scancode::RightBracket, KeyCode::Oem6),
+	(Scancode::Backslash, KeyCode::Oem5),
+	(Scancode::NonUsHash, KeyCode::Oem7),
+	(Scancode::Semicolon, KeyCode::Oem1),
+	(Scancode::Apostrophe, KeyCode::Oem3),
+	(Scancode::Grave, KeyCode::Oem8),
+	(Scancode::Comma, KeyCode::OemComma),
+	(Scancode::Period, KeyCode::OemPeriod),
+	(Scancode::Slash, KeyCode::Oem2),
+	(Scancode::NonUsBackslash, KeyCode::Oem5),
+	(Scancode::CapsLock, KeyCode::CapsLock),
+	(Scancode::F1, KeyCode::F1),
+	(Scancode::F2, KeyCode::F2),
+	(Scancode::F3, KeyCode::F3),
+	(Scancode::F4, KeyCode::F4),
+	(Scancode::F5, KeyCode::F5),
+	(Scancode::F6, KeyCode::F6),
+	(Scancode::F7, KeyCode::F7),
+	(Scancode::F8, KeyCode::F8),
+	(Scancode::F9, KeyCode::F9),
+	(Scancode::F10, KeyCode::F10),
+	(Scancode::F11, KeyCode::F11),
+	(Scancode::F12, KeyCode::F12),
+	(Scancode::PrintScreen, KeyCode::PrintScreen),
+	(Scancode::SysReq, KeyCode::SysRq),
+	(Scancode::ScrollLock, KeyCode::ScrollLock),
+	(Scancode::Pause, KeyCode::PauseBreak),
+	(Scancode::Insert, KeyCode::Insert),
+	(Scancode::Home, KeyCode::Home),
+	(Scancode::PageUp, KeyCode::PageUp),
+	(Scancode::Delete, KeyCode::Delete),
+	(Scancode::End, KeyCode::End),
+	(Scancode::PageDown, KeyCode::PageDown),
+	(Scancode::Right, KeyCode::ArrowRight),
+	(Scancode::Left, KeyCode::ArrowLeft),
+	(Scancode::Down, KeyCode::ArrowDown),
+	(Scancode::Up, KeyCode::ArrowUp),
+	(Scancode::NumLockClear, KeyCode::NumpadLock),
+	(Scancode::KpDivide, KeyCode::NumpadDivide),
+	(Scancode::KpMultiply, KeyCode::NumpadMultiply),
+	(Scancode::KpMinus, KeyCode::NumpadSubtract),
+	(Scancode::KpPlus, KeyCode::NumpadAdd),
+	(Scancode::KpEnter, KeyCode::NumpadEnter),
+	(Scancode::Kp1, KeyCode::Numpad1),
+	(Scancode::Kp2, KeyCode::Numpad2),
+	(Scancode::Kp3, KeyCode::Numpad3),
+	(Scancode::Kp4, KeyCode::Numpad4),
+	(Scancode::Kp5, KeyCode::Numpad5),
+	(Scancode::Kp6, KeyCode::Numpad6),
+	(Scancode::Kp7, KeyCode::Numpad7),
+	(Scancode::Kp8, KeyCode::Numpad8),
+	(Scancode::Kp9, KeyCode::Numpad9),
+	(Scancode::Kp0, KeyCode::Numpad0),
+	(Scancode::KpPeriod, KeyCode::NumpadPeriod),
+	(Scancode::LCtrl, KeyCode::LControl),
+	(Scancode::LShift, KeyCode::LShift),
+	(Scancode::LAlt, KeyCode::LAlt),
+	(Scancode::LGui, KeyCode::LWin),
+	(Scancode::RCtrl, KeyCode::RControl),
+	(Scancode::RShift, KeyCode::RShift),
+	(Scancode::RAlt, KeyCode::RAltGr),
+	(Scancode::RGui, KeyCode::RWin),
+	(Scancode::Application, KeyCode::Apps),
+	(Scancode::International5, KeyCode::Oem9),
+	(Scancode::International4, KeyCode::Oem10),
+	(Scancode::International2, KeyCode::Oem11),
+	(Scancode::International1, KeyCode::Oem12),
+	(Scancode::International3, KeyCode::Oem13),
+	(Scancode::AudioPrev, KeyCode::PrevTrack),
+	(Scancode::AudioNext, KeyCode::NextTrack),
+	(Scancode::AudioMute, KeyCode::Mute),
+	(Scancode::Mute, KeyCode::Mute),
+	(Scancode::Calculator, KeyCode::Calculator),
+	(Scancode::AudioPlay, KeyCode::Play),
+	(Scancode::AudioStop, KeyCode::Stop),
+	(Scancode::VolumeDown, KeyCode::VolumeDown),
+	(Scancode::VolumeUp, KeyCode::VolumeUp),
+	(Scancode::AcHome, KeyCode::WWWHome),
+];
+
+/// Which Neotron key each pix-engine key is, if we can't get a scancode.
+///
+/// This assumes a US layout, so the shifted symbols are mapped to the key they
+/// are on in a US layout. Keys not listed here (like the keypad `=` and `,`)
+/// have no Neotron equivalent.
+///
+/// pix-engine has no `Key` for the Menu (Application) key or the media and
+/// volume keys - it reports them as `Key::Unhandled` - so they can only be
+/// found by scancode, using `SCANCODES`.
+static PIX_KEYS: &[(Key, KeyCode)] = &[
+	(Key::Backspace, KeyCode::Backspace),
+	(Key::Tab, KeyCode::Tab),
+	(Key::Return, KeyCode::Return),
+	(Key::Escape, KeyCode::Escape),
+	(Key::Space, KeyCode::Spacebar),
+	(Key::Exclaim, KeyCode::Key1),
+	(Key::Quotedbl, KeyCode::Oem3),
+	(Key::Hash, KeyCode::Key3),
+	(Key::Dollar, KeyCode::Key4),
+	(Key::Percent, KeyCode::Key5),
+	(Key::Ampersand, KeyCode::Key7),
+	(Key::Quote, KeyCode::Oem3),
+	(Key::LeftParen, KeyCode::Key9),
+	(Key::RightParen, KeyCode::Key0),
+	(Key::Asterisk, KeyCode::Key8),
+	(Key::Plus, KeyCode::OemPlus),
+	(Key::Comma, KeyCode::OemComma),
+	(Key::Minus, KeyCode::OemMinus),
+	(Key::Period, KeyCode::OemPeriod),
+	(Key::Slash, KeyCode::Oem2),
+	(Key::Num0, KeyCode::Key0),
+	(Key::Num1, KeyCode::Key1),
+	(Key::Num2, KeyCode::Key2),
+	(Key::Num3, KeyCode::Key3),
+	(Key::Num4, KeyCode::Key4),
+	(Key::Num5, KeyCode::Key5),
+	(Key::Num6, KeyCode::Key6),
+	(Key::Num7, KeyCode::Key7),
+	(Key::Num8, KeyCode::Key8),
+	(Key::Num9, KeyCode::Key9),
+	(Key::Colon, KeyCode::Oem1),
+	(Key::Semicolon, KeyCode::Oem1),
+	(Key::Less, KeyCode::OemComma),
+	(Key::Equals, KeyCode::OemPlus),
+	(Key::Greater, KeyCode::OemPeriod),
+	(Key::Question, KeyCode::Oem2),
+	(Key::At, KeyCode::Key2),
+	(Key::LeftBracket, KeyCode::Oem4),
+	(Key::Backslash, KeyCode::Oem5),
+	(Key::RightBracket, KeyCode::Oem6),
+	(Key::Caret, KeyCode::Key6),
+	(Key::Underscore, KeyCode::OemMinus),
+	(Key::Backquote, KeyCode::Oem8),
+	(Key::A, KeyCode::A),
+	(Key::B, KeyCode::B),
+	(Key::C, KeyCode::C),
+	(Key::D, KeyCode::D),
+	(Key::E, KeyCode::E),
+	(Key::F, KeyCode::F),
+	(Key::G, KeyCode::G),
+	(Key::H, KeyCode::H),
+	(Key::I, KeyCode::I),
+	(Key::J, KeyCode::J),
+	(Key::K, KeyCode::K),
+	(Key::L, KeyCode::L),
+	(Key::M, KeyCode::M),
+	(Key::N, KeyCode::N),
+	(Key::O, KeyCode::O),
+	(Key::P, KeyCode::P),
+	(Key::Q, KeyCode::Q),
+	(Key::R, KeyCode::R),
+	(Key::S, KeyCode::S),
+	(Key::T, KeyCode::T),
+	(Key::U, KeyCode::U),
+	(Key::V, KeyCode::V),
+	(Key::W, KeyCode::W),
+	(Key::X, KeyCode::X),
+	(Key::Y, KeyCode::Y),
+	(Key::Z, KeyCode::Z),
+	(Key::Delete, KeyCode::Delete),
+	(Key::CapsLock, KeyCode::CapsLock),
+	(Key::F1, KeyCode::F1),
+	(Key::F2, KeyCode::F2),
+	(Key::F3, KeyCode::F3),
+	(Key::F4, KeyCode::F4),
+	(Key::F5, KeyCode::F5),
+	(Key::F6, KeyCode::F6),
+	(Key::F7, KeyCode::F7),
+	(Key::F8, KeyCode::F8),
+	(Key::F9, KeyCode::F9),
+	(Key::F10, KeyCode::F10),
+	(Key::F11, KeyCode::F11),
+	(Key::F12, KeyCode::F12),
+	(Key::PrintScreen, KeyCode::PrintScreen),
+	(Key::ScrollLock, KeyCode::ScrollLock),
+	(Key::Pause, KeyCode::PauseBreak),
+	(Key::Insert, KeyCode::Insert),
+	(Key::Home, KeyCode::Home),
+	(Key::PageUp, KeyCode::PageUp),
+	(Key::End, KeyCode::End),
+	(Key::PageDown, KeyCode::PageDown),
+	(Key::Right, KeyCode::ArrowRight),
+	(Key::Left, KeyCode::ArrowLeft),
+	(Key::Down, KeyCode::ArrowDown),
+	(Key::Up, KeyCode::ArrowUp),
+	(Key::NumLock, KeyCode::NumpadLock),
+	(Key::KpDivide, KeyCode::NumpadDivide),
+	(Key::KpMultiply, KeyCode::NumpadMultiply),
+	(Key::KpMinus, KeyCode::NumpadSubtract),
+	(Key::KpPlus, KeyCode::NumpadAdd),
+	(Key::KpEnter, KeyCode::NumpadEnter),
+	(Key::Kp1, KeyCode::Numpad1),
+	(Key::Kp2, KeyCode::Numpad2),
+	(Key::Kp3, KeyCode::Numpad3),
+	(Key::Kp4, KeyCode::Numpad4),
+	(Key::Kp5, KeyCode::Numpad5),
+	(Key::Kp6, KeyCode::Numpad6),
+	(Key::Kp7, KeyCode::Numpad7),
+	(Key::Kp8, KeyCode::Numpad8),
+	(Key::Kp9, KeyCode::Numpad9),
+	(Key::Kp0, KeyCode::Numpad0),
+	(Key::KpPeriod, KeyCode::NumpadPeriod),
+	(Key::LCtrl, KeyCode::LControl),
+	(Key::LShift, KeyCode::LShift),
+	(Key::LAlt, KeyCode::LAlt),
+	(Key::LGui, KeyCode::LWin),
+	(Key::RCtrl, KeyCode::RControl),
+	(Key::RShift, KeyCode::RShift),
+	(Key::RAlt, KeyCode::RAltGr),
+	(Key::RGui, KeyCode::RWin),
+];
+
+/// The letter keys, in alphabetical order.
+static LETTERS: [KeyCode; 26] = [
+	KeyCode::A,
+	KeyCode::B,
+	KeyCode::C,
+	KeyCode::D,
+	KeyCode::E,
+	KeyCode::F,
+	KeyCode::G,
+	KeyCode::H,
+	KeyCode::I,
+	KeyCode::J,
+	KeyCode::K,
+	KeyCode::L,
+	KeyCode::M,
+	KeyCode::N,
+	KeyCode::O,
+	KeyCode::P,
+	KeyCode::Q,
+	KeyCode::R,
+	KeyCode::S,
+	KeyCode::T,
+	KeyCode::U,
+	KeyCode::V,
+	KeyCode::W,
+	KeyCode::X,
+	KeyCode::Y,
+	KeyCode::Z,
+];
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Start watching SDL's keyboard events, so we can find out which physical key
+/// each pix-engine key event came from.
+///
+/// Call this once SDL is running.
+pub fn watch_raw_keys() {
+	unsafe {
+		sdl2::sys::SDL_AddEventWatch(Some(raw_key_watch), std::ptr::null_mut());
+	}
+}
+
+/// Called by SDL for every event, as it is queued.
+///
+/// pix-engine turns each SDL key event into a key event of its own, in the
+/// same order, so we queue up the scancodes for `from_key_event`.
+unsafe extern "C" fn raw_key_watch(
+	_userdata: *mut std::ffi::c_void,
+	event: *mut sdl2::sys::SDL_Event,
+) -> std::os::raw::c_int {
+	let event_type = (*event).type_;
+	let pressed = event_type == sdl2::sys::SDL_EventType::SDL_KEYDOWN as u32;
+	if pressed || event_type == sdl2::sys::SDL_EventType::SDL_KEYUP as u32 {
+		let keysym = (*event).key.keysym;
+		let scancode = Scancode::from_i32(keysym.scancode as i32);
+		// This is how pix-engine gets its `Key`
+		let key = Keycode::from_i32(keysym.sym).map(Key::from);
+		push_raw_key(RawKey {
+			scancode,
+			key,
+			pressed,
+		});
+	}
+	// The return value is ignored for event watches
+	0
+}
+
+/// Queue up a key event from SDL.
+fn push_raw_key(raw: RawKey) {
+	let mut raw_keys = RAW_KEYS.lock().unwrap();
+	if raw_keys.len() >= MAX_RAW_KEYS {
+		let dropped = raw_keys.pop_front();
+		debug!("Too many raw key events, dropping {:?}", dropped);
+	}
+	raw_keys.push_back(raw);
+}
+
+/// Forget the key events SDL has seen.
+///
+/// Call this when the window loses the focus, as pix-engine may never pass on
+/// the events that were waiting.
+pub fn forget_raw_keys() {
+	RAW_KEYS.lock().unwrap().clear();
+}
+
+/// Convert a key event from pix-engine into the Neotron key in the same place.
+///
+/// Every key event should be passed in, in order, even ones without a `Key`
+/// (like `ü` on a German keyboard), to keep us in step with SDL. We look for
+/// SDL's event with the same key; any before it were swallowed by pix-engine,
+/// so they are thrown away. If we didn't see SDL's event, we fall back to
+/// assuming a US layout. Returns `None` if the Neotron has no such key.
+pub fn from_key_event(key: Option<Key>, pressed: bool) -> Option<KeyCode> {
+	let mut raw_keys = RAW_KEYS.lock().unwrap();
+	if let Some(idx) = raw_keys
+		.iter()
+		.position(|raw| raw.pressed == pressed && raw.key == key)
+	{
+		for raw in raw_keys.drain(0..idx) {
+			debug!("Skipping unmatched raw key event {:?}", raw);
+		}
+		let raw = raw_keys.pop_front()?;
+		return raw.scancode.and_then(from_scancode);
+	}
+	debug!("No scancode for {:?}, assuming a US layout", key);
+	key.and_then(|key| lookup(PIX_KEYS, key))
+}
+
+/// Convert an SDL scancode into the Neotron key in the same place.
+///
+/// Returns `None` if the Neotron has no such key.
+pub fn from_scancode(scancode: Scancode) -> Option<KeyCode> {
+	lookup(SCANCODES, scancode)
+}
+
+/// Find the Neotron key with the given name, as printed by `{:?}`.
+///
+/// Only finds keys that a host keyboard can send us.
+pub fn from_name(name: &str) -> Option<KeyCode> {
+	SCANCODES
+		.iter()
+		.map(|(_, key)| *key)
+		.chain(PIX_KEYS.iter().map(|(_, key)| *key))
+		.find(|key| format!("{:?}", key) == name)
+}
+
+/// Find a key in one of our tables.
+fn lookup<K: PartialEq + Copy, V: Copy>(table: &[(K, V)], key: K) -> Option<V> {
+	table
+		.iter()
+		.find(|(table_key, _)| *table_key == key)
+		.map(|(_, value)| *value)
+}
+
+/// Work out which key types the given character, assuming the OS is using a
+/// US keyboard layout.
+///
+/// Returns the key, and whether Shift must be held down. Returns `None` for
+/// characters you can't type.
+pub fn from_char(c: char) -> Option<(KeyCode, bool)> {
+	match c {
+		'a'..='z' => Some((LETTERS[usize::from(c as u8 - b'a')], false)),
+		'A'..='Z' => Some((LETTERS[usize::from(c as u8 - b'A')], true)),
+		'\n' | '\r' => Some((KeyCode::Return, false)),
+		'\t' => Some((KeyCode::Tab, false)),
+		'\x08' | '\x7f' => Some((KeyCode::Backspace, false)),
+		'\x1b' => Some((KeyCode::Escape, false)),
+		_ => US_CHARS
+			.iter()
+			.find(|(ch, _, _)| *ch == c)
+			.map(|(_, key, shift)| (*key, *shift)),
+	}
+}
+
+/// Get the letter key for a control character (Ctrl+A is 0x01, and so on).
+pub fn from_control_char(c: u8) -> Option<KeyCode> {
+	match c {
+		0x01..=0x1A => Some(LETTERS[usize::from(c - 1)]),
+		_ => None,
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pix_keys() {
+		assert_eq!(lookup(PIX_KEYS, Key::A), Some(KeyCode::A));
+		assert_eq!(lookup(PIX_KEYS, Key::Kp0), Some(KeyCode::Numpad0));
+		assert_eq!(lookup(PIX_KEYS, Key::RAlt), Some(KeyCode::RAltGr));
+		assert_eq!(lookup(PIX_KEYS, Key::Colon), Some(KeyCode::Oem1));
+		// Shifted symbols are on the same keys as in `US_CHARS`
+		for (key, c) in [
+			(Key::Hash, '#'),
+			(Key::Exclaim, '!'),
+			(Key::At, '@'),
+			(Key::Quotedbl, '"'),
+			(Key::Underscore, '_'),
+		] {
+			assert_eq!(lookup(PIX_KEYS, key), from_char(c).map(|(code, _)| code));
+		}
+		assert_eq!(lookup(PIX_KEYS, Key::KpEquals), None);
+		assert_eq!(lookup(PIX_KEYS, Key::Unhandled), None);
+	}
+
+	#[test]
+	fn key_events() {
+		// All in one test, as they share the queue
+		let raw = |scancode, key, pressed| RawKey {
+			scancode: Some(scancode),
+			key,
+			pressed,
+		};
+		// A German keyboard's `ü` has no pix-engine key, but it's where `[`
+		// is on a US keyboard
+		push_raw_key(raw(Scancode::LeftBracket, None, true));
+		push_raw_key(raw(Scancode::LeftBracket, None, false));
+		assert_eq!(from_key_event(None, true), Some(KeyCode::Oem4));
+		assert_eq!(from_key_event(None, false), Some(KeyCode::Oem4));
+		// A French keyboard's `a` is where `q` is on a US keyboard
+		push_raw_key(raw(Scancode::Q, Some(Key::A), true));
+		assert_eq!(from_key_event(Some(Key::A), true), Some(KeyCode::Q));
+		// pix-engine calls the Menu key `Unhandled`
+		push_raw_key(raw(Scancode::Application, Some(Key::Unhandled), true));
+		assert_eq!(
+			from_key_event(Some(Key::Unhandled), true),
+			Some(KeyCode::Apps)
+		);
+		// Skip events pix-engine swallowed, even if they were the same way
+		push_raw_key(raw(Scancode::B, Some(Key::B), false));
+		push_raw_key(raw(Scancode::D, Some(Key::D), true));
+		push_raw_key(raw(Scancode::C, Some(Key::C), true));
+		assert_eq!(from_key_event(Some(Key::C), true), Some(KeyCode::C));
+		// Unknown keys are dropped
+		push_raw_key(RawKey {
+			scancode: None,
+			key: Some(Key::Unhandled),
+			pressed: true,
+		});
+		assert_eq!(from_key_event(Some(Key::Unhandled), true), None);
+		// With nothing from SDL, assume a US layout
+		assert_eq!(from_key_event(Some(Key::Z), false), Some(KeyCode::Z));
+		assert_eq!(from_key_event(None, false), None);
+		// An event we never see doesn't get matched to a later key
+		push_raw_key(raw(Scancode::E, Some(Key::E), true));
+		assert_eq!(from_key_event(Some(Key::X), true), Some(KeyCode::X));
+		assert_eq!(from_key_event(Some(Key::E), true), Some(KeyCode::E));
+		// The queue doesn't grow forever, and is emptied on focus loss
+		for _ in 0..100 {
+			push_raw_key(raw(Scancode::F, Some(Key::F), true));
+		}
+		assert_eq!(RAW_KEYS.lock().unwrap().len(), MAX_RAW_KEYS);
+		forget_raw_keys();
+		assert!(RAW_KEYS.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn menu_key() {
+		assert_eq!(from_scancode(Scancode::Application), Some(KeyCode::Apps));
+	}
+
+	#[test]
+	fn volume_keys() {
+		assert_eq!(from_scancode(Scancode::Mute), Some(KeyCode::Mute));
+		assert_eq!(from_scancode(Scancode::AudioMute), Some(KeyCode::Mute));
+		assert_eq!(from_scancode(Scancode::VolumeUp), Some(KeyCode::VolumeUp));
+		assert_eq!(
+			from_scancode(Scancode::VolumeDown),
+			Some(KeyCode::VolumeDown)
+		);
+	}
+
+	#[test]
+	fn media_keys() {
+		assert_eq!(from_scancode(Scancode::AudioPlay), Some(KeyCode::Play));
+		assert_eq!(from_scancode(Scancode::AudioStop), Some(KeyCode::Stop));
+		assert_eq!(from_scancode(Scancode::AudioPrev), Some(KeyCode::PrevTrack));
+		assert_eq!(from_scancode(Scancode::AudioNext), Some(KeyCode::NextTrack));
+		assert_eq!(
+			from_scancode(Scancode::Calculator),
+			Some(KeyCode::Calculator)
+		);
+		assert_eq!(from_scancode(Scancode::AcHome), Some(KeyCode::WWWHome));
+	}
+
+	#[test]
+	fn international_keys() {
+		assert_eq!(
+			from_scancode(Scancode::International1),
+			Some(KeyCode::Oem12)
+		);
+		assert_eq!(from_scancode(Scancode::NonUsBackslash), Some(KeyCode::Oem5));
+		assert_eq!(from_scancode(Scancode::NonUsHash), Some(KeyCode::Oem7));
+	}
+
+	#[test]
+	fn names() {
+		assert_eq!(from_name("Apps"), Some(KeyCode::Apps));
+		assert_eq!(from_name("VolumeUp"), Some(KeyCode::VolumeUp));
+		assert_eq!(from_name("LShift"), Some(KeyCode::LShift));
+		assert_eq!(from_name("NotAKey"), None);
+	}
+
+	#[test]
+	fn chars() {
+		assert_eq!(from_char('a'), Some((KeyCode::A, false)));
+		assert_eq!(from_char('Z'), Some((KeyCode::Z, true)));
+		assert_eq!(from_char('~'), Some((KeyCode::Oem8, true)));
+		assert_eq!(from_char('\n'), Some((KeyCode::Return, false)));
+		assert_eq!(from_char('é'), None);
+		assert_eq!(from_control_char(0x03), Some(KeyCode::C));
+		assert_eq!(from_control_char(0x1B), None);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -27,11 +27,12 @@
 // Imports
 // ===========================================================================
 
+use std::collections::VecDeque;
 use std::io::prelude::*;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicPtr;
 use std::sync::{
-	atomic::{AtomicU32, AtomicU8, Ordering},
+	atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering},
 	mpsc, Mutex,
 };
 
@@ -42,8 +43,22 @@ use pix_engine::prelude::*;
 
 use neotron_common_bios as common;
 
+mod audio;
+mod control;
+mod crt;
 mod font;
+mod gamepad;
+mod keymap;
+mod overlay;
 mod palette;
+mod paste;
+mod record;
+mod render;
+mod replay;
+#[cfg(unix)]
+mod terminal;
+mod textdump;
+mod wav;
 
 // ===========================================================================
 // Types
@@ -53,15 +68,122 @@ struct MyApp {
 	mode: common::video::Mode,
 	font8x16: Vec<TextureId>,
 	font8x8: Vec<TextureId>,
-	sender: mpsc::Sender<AppEvent>,
+	/// The 8x16 font RAM contents our glyph textures were drawn from
+	glyphs8x16: Vec<u8>,
+	/// The 8x8 font RAM contents our glyph textures were drawn from
+	glyphs8x8: Vec<u8>,
+	sender: mpsc::SyncSender<AppEvent>,
 	reset: bool,
+	/// Commands from the control channel
+	commands: mpsc::Receiver<control::Command>,
+	/// A host hotkey we acted on, so we swallow its key-up event too
+	hotkey_held: Option<Key>,
+	/// How big to draw each video pixel
+	scale: Scale,
+	/// Stretch modes with non-square pixels to fill a 4:3 display
+	aspect_correct: bool,
+	/// Are we filling the whole screen?
+	fullscreen: bool,
+	/// Are the CRT effects turned on?
+	crt: bool,
+	/// Which CRT effects we use, when they're turned on
+	crt_effects: crt::Effects,
+	/// The streaming texture we draw CRT-processed frames into, and its size
+	crt_texture: Option<(TextureId, usize, usize)>,
+	/// The frame, window scale and effects that made what's in `crt_texture`
+	crt_source: Option<(render::Frame, (f32, f32), crt::Effects)>,
+	/// Is the debug overlay showing?
+	overlay: bool,
+	/// The texture holding the last frame we drew, if we have one
+	screen: Option<ScreenCache>,
+	/// How many window pixels there are to each video pixel, horizontally and
+	/// vertically
+	video_scale: (f32, f32),
+	/// Where the top-left of the video image is, in window pixels
+	video_offset: (f32, f32),
+	/// What the mouse buttons are doing
+	mouse: MouseState,
+	/// The keyboard LEDs shown in the window title
+	leds_shown: Option<u8>,
+	/// Should we make the OS's lock keys match the host's at boot and when we
+	/// get the focus?
+	sync_lock_keys: bool,
+	/// How many characters per second to type when pasting
+	paste_rate: u32,
+	/// The format to save the palette in, from the hotkey
+	palette_format: palette::Format,
+	/// Should we throw away the host's auto-repeated key presses?
+	drop_key_repeats: bool,
+	/// Clipboard text to paste, once the hotkey's modifiers are released
+	paste_pending: Option<String>,
+	/// Where the game controller sticks are pointing
+	gamepads: gamepad::Gamepads,
+	/// The host audio device playing the OS's sound, if we could open one
+	playback: Option<AudioDevice<audio::Playback>>,
+	/// The audio output configuration `playback` was opened with
+	playback_generation: Option<u32>,
+	/// The host audio device recording sound for the OS, if we have one open
+	capture: Option<AudioDevice<audio::Capture>>,
+	/// The audio input configuration `capture` was opened with
+	capture_generation: u32,
+}
+
+/// The state of the host mouse, as far as the OS knows it
+#[derive(Debug, Default)]
+struct MouseState {
+	/// Is the left button down?
+	left: bool,
+	/// Is the middle button down?
+	middle: bool,
+	/// Is the right button down?
+	right: bool,
+	/// Movement we haven't reported yet, as it's less than one video pixel
+	remainder: (f32, f32),
+}
+
+/// The texture we draw the screen into, and what we drew in it.
+///
+/// Drawing every character or pixel on every frame is slow, so we keep the
+/// last frame around and only redraw the parts of it where video RAM changed.
+struct ScreenCache {
+	/// The texture, which is the size of the video mode
+	texture: TextureId,
+	/// The video mode we drew
+	mode: common::video::Mode,
+	/// A copy of the video RAM we drew from. Empty if we need a full redraw.
+	vram: Vec<u8>,
+	/// A copy of the palette we drew with
+	palette: Vec<u32>,
+	/// Whether blinking text was visible last time
+	text_blink_on: bool,
+	/// Where we drew the text cursor last time, if we drew it
+	cursor: Option<CursorCell>,
+}
+
+/// How big to draw each video pixel, horizontally
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scale {
+	/// Draw each pixel this many times wider
+	Fixed(u32),
+	/// The largest whole number that fits on the screen
+	Fit,
+	/// As big as fits on the screen, even if that's not a whole number
+	Fill,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 enum AppEvent {
 	Started,
-	KeyUp(Key),
-	KeyDown(Key),
+	KeyUp(common::hid::KeyCode),
+	KeyDown(common::hid::KeyCode),
+	/// The mouse moved by this many video pixels, and/or a button changed
+	Mouse {
+		dx: i16,
+		dy: i16,
+		left: bool,
+		middle: bool,
+		right: bool,
+	},
 }
 
 /// Our video RAM
@@ -83,6 +205,82 @@ struct Args {
 	/// Path to NVRAM file
 	#[arg(long)]
 	nvram: Option<PathBuf>,
+	/// Load the font for the 8x8 text modes from this PSF1, PSF2 or raw file
+	#[arg(long)]
+	font8: Option<PathBuf>,
+	/// Load the font for the 8x16 text modes from this PSF1, PSF2 or raw file
+	#[arg(long)]
+	font16: Option<PathBuf>,
+	/// How much to scale up the display: a whole number, `fit` for the
+	/// largest whole number that fits on the screen, or `fill` to fill the
+	/// screen even if that means uneven pixels
+	#[arg(long, default_value = "2")]
+	scale: Scale,
+	/// Stretch video modes with non-square pixels to a 4:3 shape
+	#[arg(long)]
+	aspect_correct: bool,
+	/// Start in fullscreen mode (Ctrl+Alt+F toggles it)
+	#[arg(long)]
+	fullscreen: bool,
+	/// Turn on the CRT effects at boot
+	#[arg(long)]
+	crt: bool,
+	/// Which CRT effects to use: a comma-separated list of `scanlines`, `glow` and `curvature`
+	#[arg(long, default_value = "scanlines,glow,curvature")]
+	crt_effects: crt::Effects,
+	/// Load the starting palette from this GIMP (.gpl), JASC (.pal) or hex-list file
+	#[arg(long)]
+	palette: Option<PathBuf>,
+	/// The format the palette hotkey (Ctrl+Alt+P) saves in: `gpl`, `pal` or `hex`
+	#[arg(long, default_value = "gpl")]
+	palette_format: palette::Format,
+	/// Make the OS's Caps Lock and Num Lock match the host's, at boot and
+	/// whenever the window gets the focus
+	#[arg(long)]
+	sync_lock_keys: bool,
+	/// Enable the hardware text cursor at boot
+	#[arg(long)]
+	cursor: bool,
+	/// Run without a window, rendering frames only when asked to on stdin
+	#[arg(long, conflicts_with = "terminal")]
+	headless: bool,
+	/// Run without a window, drawing text modes in this terminal
+	#[arg(long)]
+	terminal: bool,
+	/// Where the screenshot (Ctrl+Alt+S) and recording (Ctrl+Alt+R) hotkeys
+	/// save their files
+	#[arg(long, default_value = ".")]
+	screenshot_dir: PathBuf,
+	/// Record the display to this Y4M file, from boot
+	#[arg(long)]
+	record: Option<PathBuf>,
+	/// Only record one frame in every N
+	#[arg(long, default_value_t = 1)]
+	record_every: u32,
+	/// How many characters per second to type when pasting the clipboard
+	#[arg(long, default_value_t = 30)]
+	paste_rate: u32,
+	/// Record every input event the OS reads to this file
+	#[arg(long)]
+	record_input: Option<PathBuf>,
+	/// Play back input events from this file, ignoring the real keyboard and mouse
+	#[arg(long)]
+	replay: Option<PathBuf>,
+	/// Make the clock only move when the OS reads it, so runs are repeatable
+	#[arg(long)]
+	fixed_clock: bool,
+	/// Ignore the host's auto-repeat, leaving the OS to repeat held keys itself
+	#[arg(long)]
+	drop_key_repeats: bool,
+	/// Write the audio output to this WAV file, instead of playing it
+	#[arg(long)]
+	audio_wav: Option<PathBuf>,
+	/// Write the WAV file as fast as the OS produces samples, not in real time
+	#[arg(long, requires = "audio_wav")]
+	audio_wav_fast: bool,
+	/// Read the audio input from this WAV file, instead of the microphone
+	#[arg(long)]
+	audio_in: Option<PathBuf>,
 }
 
 /// All our emulated hardware
@@ -93,6 +291,37 @@ struct Hardware {
 	disk_file: Option<std::fs::File>,
 }
 
+/// The hardware text cursor, as controlled by the OS.
+///
+/// The OS finds these registers in memory region 1. They are laid out as
+/// five bytes, in the order given here.
+#[repr(C)]
+struct CursorRegisters {
+	/// Which text column the cursor is in
+	col: AtomicU8,
+	/// Which text row the cursor is in
+	row: AtomicU8,
+	/// The first scan-line of the glyph cell the cursor covers
+	start_line: AtomicU8,
+	/// The last scan-line of the glyph cell the cursor covers
+	end_line: AtomicU8,
+	/// Bit 0 shows the cursor, bit 1 makes it blink
+	control: AtomicU8,
+}
+
+/// Where the text cursor should be drawn this frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CursorCell {
+	/// Which text column the cursor is in
+	col: u16,
+	/// Which text row the cursor is in
+	row: u16,
+	/// The first scan-line of the glyph cell to fill
+	start_line: u16,
+	/// The last scan-line of the glyph cell to fill
+	end_line: u16,
+}
+
 // ===========================================================================
 // Global Variables
 // ===========================================================================
@@ -106,8 +335,29 @@ const BLOCK_SIZE: usize = 512;
 // static mut FRAMEBUFFER: [u8; 307200] = [0u8; 307200];
 static FRAMEBUFFER: Framebuffer<{ 640 * 480 }> = Framebuffer::new();
 
-/// Scale the display to make it readable on a modern monitor
-const SCALE_FACTOR: f32 = 2.0;
+/// Where the 8x16 glyphs live in font RAM
+const FONT_RAM_8X16_OFFSET: usize = 0;
+
+/// Where the 8x8 glyphs live in font RAM
+const FONT_RAM_8X8_OFFSET: usize = 256 * 16;
+
+/// The glyphs for the text modes, which the OS can rewrite at any time.
+///
+/// Holds 256 8x16 glyphs followed by 256 8x8 glyphs, one byte per row, and is
+/// shared with the OS in the same way as the framebuffer.
+static FONT_RAM: Framebuffer<{ FONT_RAM_8X8_OFFSET + (256 * 8) }> = Framebuffer::new();
+
+/// How many frames blinking text stays on (and then off) for.
+///
+/// A VGA card runs its attribute blink at 1/32 of the frame rate. Must be a
+/// power of two, as we test this bit in the frame counter.
+const TEXT_BLINK_FRAMES: u32 = 16;
+
+/// How many frames the cursor stays on (and then off) for.
+///
+/// A VGA card runs its cursor blink at 1/16 of the frame rate. Must be a
+/// power of two, as we test this bit in the frame counter.
+const CURSOR_BLINK_FRAMES: u32 = 8;
 
 /// When we booted up
 static HARDWARE: Mutex<Option<Hardware>> = Mutex::new(None);
@@ -173,6 +423,56 @@ static PALETTE: [AtomicU32; 256] = palette::make_default_palette();
 /// Defaulting to Mode 0 - 640x480 timing, 80x30 text mode
 static VIDEO_MODE: AtomicU8 = AtomicU8::new(0);
 
+/// The text cursor registers we share with the OS.
+static CURSOR: CursorRegisters = CursorRegisters::new();
+
+/// How many frames we have drawn, for timing the blink effects
+static FRAME_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Where hotkey screenshots are saved.
+static SCREENSHOT_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// The video recording in progress, if any.
+static RECORDER: Mutex<Option<record::Recorder>> = Mutex::new(None);
+
+/// We record one frame in this many.
+static RECORD_EVERY: AtomicU32 = AtomicU32::new(1);
+
+/// The keyboard LEDs, as last set by the OS (see `leds_to_bits`).
+static KEYBOARD_LEDS: AtomicU8 = AtomicU8::new(0);
+
+/// The input events the OS reads are written here, if we're recording them.
+static INPUT_LOG: Mutex<Option<replay::EventLog>> = Mutex::new(None);
+
+/// The input events we're playing back, if any.
+static REPLAY: Mutex<Option<replay::Replay>> = Mutex::new(None);
+
+/// Does the clock only move when the OS reads it?
+static FIXED_CLOCK: AtomicBool = AtomicBool::new(false);
+
+/// The tick count, when we have a fixed clock.
+static FIXED_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// How many HID events we queue up for the OS before we start dropping them.
+///
+/// This matches the 16 byte buffer in a PC keyboard controller.
+const HID_QUEUE_DEPTH: usize = 16;
+
+/// How many key presses we've dropped because the OS didn't read them in time.
+static HID_OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+
+/// Key releases that didn't fit in the HID queue.
+///
+/// We never drop a release, or the OS would think the key was stuck down.
+/// While any are waiting here, new key events queue up behind them.
+static PENDING_RELEASES: Mutex<VecDeque<common::hid::KeyCode>> = Mutex::new(VecDeque::new());
+
+/// Mouse events for the OS.
+///
+/// These are kept apart from the keys, and movements with the same buttons
+/// held are added together, so moving the mouse can't fill the HID queue.
+static MOUSE_QUEUE: Mutex<VecDeque<AppEvent>> = Mutex::new(VecDeque::new());
+
 /// HID events come from here
 static EV_QUEUE: Mutex<Option<mpsc::Receiver<AppEvent>>> = Mutex::new(None);
 
@@ -229,34 +529,72 @@ fn main() {
 	let lib = unsafe { libloading::Library::new(args.os).expect("library to load") };
 	println!("Loaded!");
 
+	if let Some(path) = args.font8.as_deref() {
+		info!("Loading 8x8 font from: {}", path.display());
+		font::load_font8(path).expect("load 8x8 font");
+	}
+
+	if let Some(path) = args.font16.as_deref() {
+		info!("Loading 8x16 font from: {}", path.display());
+		font::load_font16(path).expect("load 8x16 font");
+	}
+
+	if let Some(path) = args.palette.as_deref() {
+		info!("Loading palette from: {}", path.display());
+		let colours = palette::load(path).expect("load palette");
+		if colours.len() > PALETTE.len() {
+			log::warn!("Palette has {} colours, only using 256", colours.len());
+		}
+		for (entry, colour) in PALETTE.iter().zip(colours.iter()) {
+			entry.store(colour.as_packed(), Ordering::Relaxed);
+		}
+	}
+
+	load_font_ram(font::active_font16(), FONT_RAM_8X16_OFFSET);
+	load_font_ram(font::active_font8(), FONT_RAM_8X8_OFFSET);
+
 	if let Some(config_path) = args.nvram {
 		info!("Loading OS config from: {}", config_path.display());
 		*CONFIG_FILE_PATH.lock().unwrap() = Some(config_path);
 	}
 
-	let default_mode = unsafe { common::video::Mode::from_u8(0) };
-	let width = (default_mode.horizontal_pixels() as f32) * SCALE_FACTOR;
-	let height = (default_mode.vertical_lines() as f32) * SCALE_FACTOR;
-	info!("Default Window set to {} x {}", width, height);
+	*SCREENSHOT_DIR.lock().unwrap() = Some(args.screenshot_dir);
 
-	// Make a window
-	let mut engine = Engine::builder()
-		.dimensions(width as u32, height as u32)
-		.scale(SCALE_FACTOR, SCALE_FACTOR)
-		.title("Neotron Desktop BIOS")
-		.show_frame_rate()
-		.target_frame_rate(60)
-		.build()
-		.unwrap();
-	let (sender, receiver) = mpsc::channel();
-	let mut app = MyApp {
-		mode: default_mode,
-		font8x16: Vec::new(),
-		font8x8: Vec::new(),
-		sender,
-		reset: true,
-	};
+	RECORD_EVERY.store(args.record_every, Ordering::Relaxed);
+	if let Some(path) = args.record.as_deref() {
+		start_recording(path);
+	}
+
+	FIXED_CLOCK.store(args.fixed_clock, Ordering::Relaxed);
 
+	if let Some(path) = args.audio_wav.as_deref() {
+		audio::start_wav(path, args.audio_wav_fast);
+	}
+
+	if let Some(path) = args.audio_in.as_deref() {
+		audio::start_wav_input(path).expect("load audio input file");
+	}
+
+	if let Some(path) = args.record_input.as_deref() {
+		info!("Recording input to: {}", path.display());
+		let log = replay::EventLog::create(path).expect("create input log");
+		*INPUT_LOG.lock().unwrap() = Some(log);
+	}
+
+	if let Some(path) = args.replay.as_deref() {
+		info!("Replaying input from: {}", path.display());
+		let events = replay::Replay::load(path).expect("load input log");
+		*REPLAY.lock().unwrap() = Some(events);
+	}
+
+	if args.cursor {
+		CURSOR.control.store(
+			CursorRegisters::CONTROL_VISIBLE | CursorRegisters::CONTROL_BLINK,
+			Ordering::Relaxed,
+		);
+	}
+
+	let (sender, receiver) = mpsc::sync_channel(HID_QUEUE_DEPTH);
 	EV_QUEUE.lock().unwrap().replace(receiver);
 
 	// Run the OS
@@ -272,9 +610,235 @@ fn main() {
 		main_func(&BIOS_API);
 	});
 
+	if args.terminal || args.headless {
+		// No window, so no speakers either
+		audio::discard_output();
+	}
+
+	if args.terminal {
+		// Our stdin is the keyboard, so there's no control channel
+		#[cfg(unix)]
+		terminal::run(sender);
+		#[cfg(not(unix))]
+		panic!("The terminal front-end is only supported on Unix");
+	}
+
+	let commands = control::spawn_stdin_reader();
+
+	if args.headless {
+		run_headless(sender, commands);
+	}
+
+	let default_mode = unsafe { common::video::Mode::from_u8(0) };
+	// We don't know how big the screen is yet, so `fit` starts at 1x and
+	// gets fixed up on the first frame.
+	let (scale_x, scale_y) = scale_factors(args.scale, args.aspect_correct, default_mode, None);
+	let width = (default_mode.horizontal_pixels() as f32) * scale_x;
+	let height = (default_mode.vertical_lines() as f32) * scale_y;
+	info!("Default Window set to {} x {}", width, height);
+
+	// Make a window
+	let mut builder = Engine::builder();
+	builder
+		.dimensions(width as u32, height as u32)
+		.scale(scale_x, scale_y)
+		.title("Neotron Desktop BIOS")
+		.show_frame_rate()
+		.target_frame_rate(60);
+	if args.fullscreen {
+		builder.fullscreen();
+	}
+	let mut engine = builder.build().unwrap();
+	let mut app = MyApp {
+		mode: default_mode,
+		font8x16: Vec::new(),
+		font8x8: Vec::new(),
+		glyphs8x16: Vec::new(),
+		glyphs8x8: Vec::new(),
+		sender,
+		reset: true,
+		commands,
+		hotkey_held: None,
+		scale: args.scale,
+		aspect_correct: args.aspect_correct,
+		fullscreen: args.fullscreen,
+		crt: args.crt,
+		crt_effects: args.crt_effects,
+		crt_texture: None,
+		crt_source: None,
+		overlay: false,
+		screen: None,
+		video_scale: (scale_x, scale_y),
+		video_offset: (0.0, 0.0),
+		mouse: MouseState::default(),
+		leds_shown: None,
+		sync_lock_keys: args.sync_lock_keys,
+		paste_rate: args.paste_rate,
+		palette_format: args.palette_format,
+		drop_key_repeats: args.drop_key_repeats,
+		paste_pending: None,
+		gamepads: gamepad::Gamepads::default(),
+		playback: None,
+		playback_generation: None,
+		capture: None,
+		capture_generation: 0,
+	};
+
 	engine.run(&mut app).unwrap();
 }
 
+/// Run the OS with no window at all.
+///
+/// There's no SDL here - we just tick the video frame counter at 60 Hz, so
+/// that blinking text and the cursor behave as they would on screen. Frames are
+/// only drawn when a control command on stdin asks for one.
+fn run_headless(
+	sender: mpsc::SyncSender<AppEvent>,
+	commands: mpsc::Receiver<control::Command>,
+) -> ! {
+	info!("Running headless");
+	// Let the rest of the OS start now
+	sender.send(AppEvent::Started).unwrap();
+	let frame_period = std::time::Duration::from_micros(1_000_000 / 60);
+	let mut next_frame = std::time::Instant::now();
+	loop {
+		next_frame += frame_period;
+		if let Some(delay) = next_frame.checked_duration_since(std::time::Instant::now()) {
+			std::thread::sleep(delay);
+		}
+		FRAME_COUNT.fetch_add(1, Ordering::Relaxed);
+		record_frame();
+		while let Ok(command) = commands.try_recv() {
+			run_command(command);
+		}
+	}
+}
+
+/// Carry out a command from the control channel.
+fn run_command(command: control::Command) {
+	match command {
+		control::Command::Frame(path) => {
+			let frame = render::Frame::capture();
+			let result = std::fs::File::create(&path).and_then(|file| {
+				let mut writer = std::io::BufWriter::new(file);
+				frame.write_ppm(&mut writer)?;
+				writer.flush()
+			});
+			match result {
+				Ok(()) => info!("Wrote frame to {}", path.display()),
+				Err(e) => log::warn!("Failed to write frame to {}: {:?}", path.display(), e),
+			}
+		}
+		control::Command::Screenshot(path) => save_screenshot(&path),
+		control::Command::RecordStart(path) => start_recording(&path),
+		control::Command::RecordStop => stop_recording(),
+		control::Command::DumpText(path) => save_text_dump(&path, textdump::to_utf8),
+		control::Command::DumpAnsi(path) => save_text_dump(&path, textdump::to_ansi),
+		control::Command::DumpPalette(path) => save_palette(&path),
+	}
+}
+
+/// Save the current palette to a file, in the format given by its extension.
+fn save_palette(path: &std::path::Path) {
+	let colours: Vec<RGBColour> = PALETTE
+		.iter()
+		.map(|entry| RGBColour::from_packed(entry.load(Ordering::Relaxed)))
+		.collect();
+	match palette::save(path, &colours) {
+		Ok(()) => info!("Saved palette to {}", path.display()),
+		Err(e) => log::warn!("Failed to save palette to {}: {:?}", path.display(), e),
+	}
+}
+
+/// Dump the text on screen to a file, or to stdout if the path is `-`.
+fn save_text_dump(path: &std::path::Path, dump: fn(common::video::Mode) -> Option<String>) {
+	let Some(text) = dump(video_get_mode()) else {
+		log::warn!("Can't dump the screen as text - not in a text mode");
+		return;
+	};
+	if path == std::path::Path::new("-") {
+		print!("{}", text);
+		return;
+	}
+	match std::fs::write(path, text) {
+		Ok(()) => info!("Dumped screen text to {}", path.display()),
+		Err(e) => log::warn!("Failed to dump screen text to {}: {:?}", path.display(), e),
+	}
+}
+
+/// Render the current frame to a PNG file.
+fn save_screenshot(path: &std::path::Path) {
+	let frame = render::Frame::capture();
+	let result =
+		std::fs::File::create(path).and_then(|file| frame.write_png(std::io::BufWriter::new(file)));
+	match result {
+		Ok(()) => info!("Saved screenshot to {}", path.display()),
+		Err(e) => log::warn!("Failed to save screenshot to {}: {:?}", path.display(), e),
+	}
+}
+
+/// Pick a fresh file name for a hotkey screenshot or recording.
+///
+/// Files are named after the number of milliseconds since boot. We only look
+/// at the clock - reading it through `time_ticks_get` would move a fixed clock
+/// on, and the OS would see it. A fixed clock may not have moved since the
+/// last file, so we add a number to the name if we need to.
+fn screenshot_path(extension: &str) -> PathBuf {
+	let dir = SCREENSHOT_DIR.lock().unwrap().clone().unwrap_or_default();
+	let ticks = current_ticks();
+	let mut path = dir.join(format!("neotron-{:010}.{}", ticks, extension));
+	let mut count = 1;
+	while path.exists() {
+		path = dir.join(format!("neotron-{:010}-{}.{}", ticks, count, extension));
+		count += 1;
+	}
+	path
+}
+
+/// Start recording the display to a Y4M file.
+///
+/// Any recording already in progress is finished first.
+fn start_recording(path: &std::path::Path) {
+	stop_recording();
+	let first_frame = render::Frame::capture();
+	let every = RECORD_EVERY.load(Ordering::Relaxed);
+	match record::Recorder::new(path, &first_frame, every) {
+		Ok(recorder) => {
+			info!("Recording to {}", path.display());
+			*RECORDER.lock().unwrap() = Some(recorder);
+		}
+		Err(e) => log::warn!("Failed to start recording to {}: {:?}", path.display(), e),
+	}
+}
+
+/// Stop recording the display, if we were.
+fn stop_recording() {
+	if let Some(recorder) = RECORDER.lock().unwrap().take() {
+		match recorder.finish() {
+			Ok(()) => info!("Recording stopped"),
+			Err(e) => log::warn!("Failed to finish recording: {:?}", e),
+		}
+	}
+}
+
+/// Add the current frame to the recording, if there is one.
+///
+/// Called once per displayed frame.
+fn record_frame() {
+	let mut recorder_guard = RECORDER.lock().unwrap();
+	let Some(recorder) = recorder_guard.as_mut() else {
+		return;
+	};
+	if !recorder.wants_frame() {
+		return;
+	}
+	let frame = render::Frame::capture();
+	if let Err(e) = recorder.write_frame(&frame) {
+		log::warn!("Failed to record frame, stopping: {:?}", e);
+		*recorder_guard = None;
+	}
+}
+
 /// Returns the version number of the BIOS API.
 extern "C" fn api_version_get() -> common::Version {
 	debug!("api_version_get()");
@@ -361,8 +925,17 @@ extern "C" fn serial_read(
 ///
 /// If the BIOS does not have a battery-backed clock, or if that battery has
 /// failed to keep time, the system starts up assuming it is the epoch.
+///
+/// With a fixed clock, the time is the epoch plus the tick count.
 extern "C" fn time_clock_get() -> common::Time {
 	debug!("time_clock_get()");
+	if FIXED_CLOCK.load(Ordering::Relaxed) {
+		let ticks = FIXED_TICKS.load(Ordering::Relaxed);
+		return common::Time {
+			secs: (ticks / 1000) as u32,
+			nsecs: ((ticks % 1000) * 1_000_000) as u32,
+		};
+	}
 	// 946684800 seconds between 2000-01-01 and 1970-01-01
 	let epoch = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(946684800);
 	let difference = epoch.elapsed().unwrap_or_default();
@@ -517,6 +1090,13 @@ extern "C" fn video_mode_needs_vram(_mode: common::video::Mode) -> bool {
 /// application space available). The OS will prefer lower numbered regions
 /// (other than Region 0), so faster memory should be listed first.
 ///
+/// Region 1 is not memory at all - it holds the text cursor registers (see
+/// `CursorRegisters`), and is marked as reserved so the OS doesn't allocate
+/// from it.
+///
+/// Region 2 is font RAM (see `FONT_RAM`), and is also marked as reserved.
+/// Changes to the glyphs show up on the next frame.
+///
 /// If the region number given is invalid, the function returns `(null, 0)`.
 extern "C" fn memory_get_region(region: u8) -> common::FfiOption<common::MemoryRegion> {
 	static mut MEMORY_BLOCK: (*mut u8, usize) = (std::ptr::null_mut(), 0);
@@ -537,6 +1117,16 @@ extern "C" fn memory_get_region(region: u8) -> common::FfiOption<common::MemoryR
 				kind: common::FfiMemoryKind::from(common::MemoryKind::Ram),
 			})
 		}
+		1 => common::FfiOption::Some(common::MemoryRegion {
+			start: &CURSOR as *const CursorRegisters as *mut u8,
+			length: std::mem::size_of::<CursorRegisters>(),
+			kind: common::FfiMemoryKind::from(common::MemoryKind::Reserved),
+		}),
+		2 => common::FfiOption::Some(common::MemoryRegion {
+			start: FONT_RAM.get_pointer() as *mut u8,
+			length: FONT_RAM.len(),
+			kind: common::FfiMemoryKind::from(common::MemoryKind::Reserved),
+		}),
 		_ => common::FfiOption::None,
 	}
 }
@@ -544,16 +1134,53 @@ extern "C" fn memory_get_region(region: u8) -> common::FfiOption<common::MemoryR
 /// Get the next available HID event, if any.
 ///
 /// This function doesn't block. It will return `Ok(None)` if there is no event ready.
+///
+/// When replaying, events come from the input log instead and live input is
+/// thrown away.
 extern "C" fn hid_get_event() -> common::ApiResult<common::FfiOption<common::hid::HidEvent>> {
 	let queue = EV_QUEUE.lock().unwrap();
-	match queue.as_ref().unwrap().try_recv() {
-		Ok(AppEvent::KeyUp(key)) => {
-			let code = common::hid::HidEvent::KeyRelease(convert_keycode(key));
+	let queue = queue.as_ref().unwrap();
+	let mut replay = REPLAY.lock().unwrap();
+	let event = match replay.as_mut() {
+		Some(replay) => {
+			while next_live_event(queue).is_some() {}
+			replay.next_event(current_ticks())
+		}
+		None => next_live_event(queue),
+	};
+	if replay.as_ref().is_some_and(replay::Replay::is_finished) {
+		info!("Replay finished, back to the real keyboard and mouse");
+		*replay = None;
+	}
+	drop(replay);
+	if let (Some(log), Some(event)) = (INPUT_LOG.lock().unwrap().as_mut(), event.as_ref()) {
+		if let Err(e) = log.write(current_ticks(), event) {
+			log::warn!("Failed to record input: {:?}", e);
+		}
+	}
+	match event {
+		Some(AppEvent::KeyUp(key)) => {
+			let code = common::hid::HidEvent::KeyRelease(key);
+			debug!("hid_get_event() -> {:?}", code);
+			common::ApiResult::Ok(common::FfiOption::Some(code))
+		}
+		Some(AppEvent::KeyDown(key)) => {
+			let code = common::hid::HidEvent::KeyPress(key);
 			debug!("hid_get_event() -> {:?}", code);
 			common::ApiResult::Ok(common::FfiOption::Some(code))
 		}
-		Ok(AppEvent::KeyDown(key)) => {
-			let code = common::hid::HidEvent::KeyPress(convert_keycode(key));
+		Some(AppEvent::Mouse {
+			dx,
+			dy,
+			left,
+			middle,
+			right,
+		}) => {
+			let code = common::hid::HidEvent::MouseInput(common::hid::MouseData {
+				buttons: mouse_buttons(left, middle, right),
+				x: dx,
+				y: dy,
+			});
 			debug!("hid_get_event() -> {:?}", code);
 			common::ApiResult::Ok(common::FfiOption::Some(code))
 		}
@@ -561,139 +1188,151 @@ extern "C" fn hid_get_event() -> common::ApiResult<common::FfiOption<common::hid
 	}
 }
 
-/// Convert a pix-engine keycode into a Neotron BIOS keycode
-fn convert_keycode(key: Key) -> common::hid::KeyCode {
-	match key {
-		Key::Backspace => common::hid::KeyCode::Backspace,
-		Key::Tab => common::hid::KeyCode::Tab,
-		Key::Return => common::hid::KeyCode::Return,
-		Key::Escape => common::hid::KeyCode::Escape,
-		Key::Space => common::hid::KeyCode::Spacebar,
-		// Key::Exclaim => common::hid::KeyCode::Exclaim,
-		// Key::Quotedbl => common::hid::KeyCode::Quotedbl,
-		Key::Hash => common::hid::KeyCode::Oem7,
-		// Key::Dollar => common::hid::KeyCode::Dollar,
-		// Key::Percent => common::hid::KeyCode::Percent,
-		// Key::Ampersand => common::hid::KeyCode::Ampersand,
-		Key::Quote => common::hid::KeyCode::Oem3,
-		// Key::LeftParen => common::hid::KeyCode::LeftParen,
-		// Key::RightParen => common::hid::KeyCode::RightParen,
-		// Key::Asterisk => common::hid::KeyCode::Asterisk,
-		// Key::Plus => common::hid::KeyCode::Plus,
-		Key::Comma => common::hid::KeyCode::OemComma,
-		Key::Minus => common::hid::KeyCode::OemMinus,
-		Key::Period => common::hid::KeyCode::OemPeriod,
-		Key::Slash => common::hid::KeyCode::Oem2,
-		Key::Num0 => common::hid::KeyCode::Key0,
-		Key::Num1 => common::hid::KeyCode::Key1,
-		Key::Num2 => common::hid::KeyCode::Key2,
-		Key::Num3 => common::hid::KeyCode::Key3,
-		Key::Num4 => common::hid::KeyCode::Key4,
-		Key::Num5 => common::hid::KeyCode::Key5,
-		Key::Num6 => common::hid::KeyCode::Key6,
-		Key::Num7 => common::hid::KeyCode::Key7,
-		Key::Num8 => common::hid::KeyCode::Key8,
-		Key::Num9 => common::hid::KeyCode::Key9,
-		// Key::Colon => common::hid::KeyCode::Colon,
-		Key::Semicolon => common::hid::KeyCode::Oem1,
-		// Key::Less => common::hid::KeyCode::Less,
-		Key::Equals => common::hid::KeyCode::OemPlus,
-		// Key::Greater => common::hid::KeyCode::Greater,
-		// Key::Question => common::hid::KeyCode::Question,
-		// Key::At => common::hid::KeyCode::At,
-		Key::LeftBracket => common::hid::KeyCode::Oem4,
-		Key::Backslash => common::hid::KeyCode::Oem5,
-		Key::RightBracket => common::hid::KeyCode::Oem6,
-		// Key::Caret => common::hid::KeyCode::Caret,
-		// Key::Underscore => common::hid::KeyCode::Underscore,
-		Key::Backquote => common::hid::KeyCode::Oem8,
-		Key::A => common::hid::KeyCode::A,
-		Key::B => common::hid::KeyCode::B,
-		Key::C => common::hid::KeyCode::C,
-		Key::D => common::hid::KeyCode::D,
-		Key::E => common::hid::KeyCode::E,
-		Key::F => common::hid::KeyCode::F,
-		Key::G => common::hid::KeyCode::G,
-		Key::H => common::hid::KeyCode::H,
-		Key::I => common::hid::KeyCode::I,
-		Key::J => common::hid::KeyCode::J,
-		Key::K => common::hid::KeyCode::K,
-		Key::L => common::hid::KeyCode::L,
-		Key::M => common::hid::KeyCode::M,
-		Key::N => common::hid::KeyCode::N,
-		Key::O => common::hid::KeyCode::O,
-		Key::P => common::hid::KeyCode::P,
-		Key::Q => common::hid::KeyCode::Q,
-		Key::R => common::hid::KeyCode::R,
-		Key::S => common::hid::KeyCode::S,
-		Key::T => common::hid::KeyCode::T,
-		Key::U => common::hid::KeyCode::U,
-		Key::V => common::hid::KeyCode::V,
-		Key::W => common::hid::KeyCode::W,
-		Key::X => common::hid::KeyCode::X,
-		Key::Y => common::hid::KeyCode::Y,
-		Key::Z => common::hid::KeyCode::Z,
-		Key::Delete => common::hid::KeyCode::Delete,
-		Key::CapsLock => common::hid::KeyCode::CapsLock,
-		Key::F1 => common::hid::KeyCode::F1,
-		Key::F2 => common::hid::KeyCode::F2,
-		Key::F3 => common::hid::KeyCode::F3,
-		Key::F4 => common::hid::KeyCode::F4,
-		Key::F5 => common::hid::KeyCode::F5,
-		Key::F6 => common::hid::KeyCode::F6,
-		Key::F7 => common::hid::KeyCode::F7,
-		Key::F8 => common::hid::KeyCode::F8,
-		Key::F9 => common::hid::KeyCode::F9,
-		Key::F10 => common::hid::KeyCode::F10,
-		Key::F11 => common::hid::KeyCode::F11,
-		Key::F12 => common::hid::KeyCode::F12,
-		Key::PrintScreen => common::hid::KeyCode::PrintScreen,
-		Key::ScrollLock => common::hid::KeyCode::ScrollLock,
-		Key::Pause => common::hid::KeyCode::PauseBreak,
-		Key::Insert => common::hid::KeyCode::Insert,
-		Key::Home => common::hid::KeyCode::Home,
-		Key::PageUp => common::hid::KeyCode::PageUp,
-		Key::End => common::hid::KeyCode::End,
-		Key::PageDown => common::hid::KeyCode::PageDown,
-		Key::Right => common::hid::KeyCode::ArrowRight,
-		Key::Left => common::hid::KeyCode::ArrowLeft,
-		Key::Down => common::hid::KeyCode::ArrowDown,
-		Key::Up => common::hid::KeyCode::ArrowUp,
-		Key::NumLock => common::hid::KeyCode::NumpadLock,
-		Key::KpDivide => common::hid::KeyCode::NumpadDivide,
-		Key::KpMultiply => common::hid::KeyCode::NumpadMultiply,
-		Key::KpMinus => common::hid::KeyCode::NumpadSubtract,
-		Key::KpPlus => common::hid::KeyCode::NumpadAdd,
-		Key::KpEnter => common::hid::KeyCode::NumpadEnter,
-		Key::Kp1 => common::hid::KeyCode::Numpad1,
-		Key::Kp2 => common::hid::KeyCode::Numpad2,
-		Key::Kp3 => common::hid::KeyCode::Numpad3,
-		Key::Kp4 => common::hid::KeyCode::Numpad4,
-		Key::Kp5 => common::hid::KeyCode::Numpad5,
-		Key::Kp6 => common::hid::KeyCode::Numpad6,
-		Key::Kp7 => common::hid::KeyCode::Numpad7,
-		Key::Kp8 => common::hid::KeyCode::Numpad8,
-		Key::Kp9 => common::hid::KeyCode::Numpad9,
-		Key::Kp0 => common::hid::KeyCode::Numpad0,
-		Key::KpPeriod => common::hid::KeyCode::NumpadPeriod,
-		// Key::KpEquals => common::hid::KeyCode::KpEquals,
-		// Key::KpComma => common::hid::KeyCode::KpComma,
-		Key::LCtrl => common::hid::KeyCode::LControl,
-		Key::LShift => common::hid::KeyCode::LShift,
-		Key::LAlt => common::hid::KeyCode::LAlt,
-		Key::LGui => common::hid::KeyCode::LWin,
-		Key::RCtrl => common::hid::KeyCode::RControl,
-		Key::RShift => common::hid::KeyCode::RShift,
-		Key::RAlt => common::hid::KeyCode::RAltGr,
-		Key::RGui => common::hid::KeyCode::RWin,
-		_ => common::hid::KeyCode::X,
+/// Take the next live input event, if there is one.
+///
+/// Keys come first, then any releases that didn't fit in the queue, then the
+/// mouse.
+fn next_live_event(queue: &mpsc::Receiver<AppEvent>) -> Option<AppEvent> {
+	if let Ok(event) = queue.try_recv() {
+		return Some(event);
+	}
+	if let Some(key) = PENDING_RELEASES.lock().unwrap().pop_front() {
+		return Some(AppEvent::KeyUp(key));
+	}
+	MOUSE_QUEUE.lock().unwrap().pop_front()
+}
+
+/// Make the BIOS API's record of which mouse buttons are held down.
+fn mouse_buttons(left: bool, middle: bool, right: bool) -> common::hid::MouseButtons {
+	let mut buttons = common::hid::MouseButtons::new();
+	if left {
+		buttons = buttons.set_left_pressed();
 	}
+	if middle {
+		buttons = buttons.set_middle_pressed();
+	}
+	if right {
+		buttons = buttons.set_right_pressed();
+	}
+	buttons
 }
 
 /// Control the keyboard LEDs.
-extern "C" fn hid_set_leds(_leds: common::hid::KeyboardLeds) -> common::ApiResult<()> {
-	debug!("hid_set_leds()");
-	Err(common::Error::Unimplemented).into()
+///
+/// We have no real LEDs, so we show them in the window title instead.
+extern "C" fn hid_set_leds(leds: common::hid::KeyboardLeds) -> common::ApiResult<()> {
+	debug!("hid_set_leds({:?})", leds);
+	KEYBOARD_LEDS.store(leds_to_bits(leds), Ordering::Relaxed);
+	common::ApiResult::Ok(())
+}
+
+/// Queue a live input event for the OS.
+///
+/// A real keyboard controller has nowhere to put key presses when its buffer
+/// fills up, so we drop them too, and count how many we've lost. Key releases
+/// are never dropped, and mouse events have a queue of their own.
+fn send_event(sender: &mpsc::SyncSender<AppEvent>, event: AppEvent) {
+	if let AppEvent::Mouse { .. } = event {
+		queue_mouse(event);
+		return;
+	}
+	let mut pending = PENDING_RELEASES.lock().unwrap();
+	// Nothing may overtake a release that's waiting for space
+	let result = if pending.is_empty() {
+		sender.try_send(event)
+	} else {
+		Err(mpsc::TrySendError::Full(event))
+	};
+	match result {
+		Ok(()) => {}
+		Err(mpsc::TrySendError::Full(AppEvent::KeyUp(key))) => {
+			debug!("HID queue full, holding on to release of {:?}", key);
+			if !pending.contains(&key) {
+				pending.push_back(key);
+			}
+		}
+		Err(mpsc::TrySendError::Full(event)) => {
+			let count = HID_OVERFLOWS.fetch_add(1, Ordering::Relaxed) + 1;
+			// Don't flood the log if the OS has stopped reading events
+			if count.is_power_of_two() {
+				log::warn!(
+					"HID queue full, dropped {:?} ({} key presses dropped so far)",
+					event,
+					count
+				);
+			} else {
+				debug!("HID queue full, dropped {:?}", event);
+			}
+		}
+		Err(mpsc::TrySendError::Disconnected(_)) => {
+			panic!("The OS has stopped taking HID events");
+		}
+	}
+}
+
+/// Queue a live input event for the OS, waiting for space if the queue is
+/// full.
+///
+/// Like `send_event`, this never overtakes a release that's waiting for space.
+/// Fails if the OS has stopped taking events.
+fn send_event_waiting(
+	sender: &mpsc::SyncSender<AppEvent>,
+	mut event: AppEvent,
+) -> Result<(), mpsc::SendError<AppEvent>> {
+	loop {
+		let pending = PENDING_RELEASES.lock().unwrap();
+		if pending.is_empty() {
+			match sender.try_send(event) {
+				Ok(()) => return Ok(()),
+				Err(mpsc::TrySendError::Full(unsent)) => event = unsent,
+				Err(mpsc::TrySendError::Disconnected(unsent)) => {
+					return Err(mpsc::SendError(unsent))
+				}
+			}
+		}
+		// Don't hold the lock while we wait, or live input would have to wait
+		// too
+		drop(pending);
+		std::thread::sleep(std::time::Duration::from_millis(1));
+	}
+}
+
+/// Queue a mouse event for the OS.
+///
+/// If the last event waiting has the same buttons held, we just add this
+/// movement to it. Otherwise it's a click, and it gets an entry of its own.
+fn queue_mouse(event: AppEvent) {
+	let mut queue = MOUSE_QUEUE.lock().unwrap();
+	if let (
+		Some(AppEvent::Mouse {
+			dx,
+			dy,
+			left,
+			middle,
+			right,
+		}),
+		AppEvent::Mouse {
+			dx: new_dx,
+			dy: new_dy,
+			left: new_left,
+			middle: new_middle,
+			right: new_right,
+		},
+	) = (queue.back_mut(), &event)
+	{
+		if (*left, *middle, *right) == (*new_left, *new_middle, *new_right) {
+			*dx = dx.saturating_add(*new_dx);
+			*dy = dy.saturating_add(*new_dy);
+			return;
+		}
+	}
+	if queue.len() >= HID_QUEUE_DEPTH {
+		// Only frantic clicking gets us here; the oldest click is the least
+		// interesting
+		let dropped = queue.pop_front();
+		debug!("Mouse queue full, dropped {:?}", dropped);
+	}
+	queue.push_back(event);
 }
 
 /// Wait for the next occurence of the specified video scan-line.
@@ -772,59 +1411,96 @@ extern "C" fn i2c_write_read(
 	common::ApiResult::Err(common::Error::Unimplemented)
 }
 
+/// Get information about one of the audio mixer channels.
+///
+/// We have Line Out (0), Headphones (1) and Line In (2).
 extern "C" fn audio_mixer_channel_get_info(
-	_audio_mixer_id: u8,
+	audio_mixer_id: u8,
 ) -> common::FfiOption<common::audio::MixerChannelInfo> {
-	debug!("audio_mixer_channel_get_info");
-	common::FfiOption::None
+	debug!("audio_mixer_channel_get_info({})", audio_mixer_id);
+	audio::mixer_channel_info(audio_mixer_id).into()
 }
 
+/// Set the level of one of the audio mixer channels.
 extern "C" fn audio_mixer_channel_set_level(
-	_audio_mixer_id: u8,
-	_level: u8,
+	audio_mixer_id: u8,
+	level: u8,
 ) -> common::ApiResult<()> {
-	debug!("audio_mixer_channel_set_level");
-	common::ApiResult::Err(common::Error::Unimplemented)
+	debug!(
+		"audio_mixer_channel_set_level({}, {})",
+		audio_mixer_id, level
+	);
+	audio::set_mixer_level(audio_mixer_id, level).into()
 }
 
-extern "C" fn audio_output_set_config(_config: common::audio::Config) -> common::ApiResult<()> {
-	debug!("audio_output_set_config");
-	common::ApiResult::Err(common::Error::Unimplemented)
+/// Set the sample format and sample rate for audio output.
+///
+/// Any samples that haven't been played yet are thrown away.
+extern "C" fn audio_output_set_config(config: common::audio::Config) -> common::ApiResult<()> {
+	debug!("audio_output_set_config({:?})", config);
+	audio::set_output_config(config).into()
 }
 
+/// Get the sample format and sample rate for audio output.
 extern "C" fn audio_output_get_config() -> common::ApiResult<common::audio::Config> {
-	debug!("audio_output_get_config");
-	common::ApiResult::Err(common::Error::Unimplemented)
+	debug!("audio_output_get_config()");
+	common::ApiResult::Ok(audio::output_config())
 }
 
-unsafe extern "C" fn audio_output_data(_samples: common::FfiByteSlice) -> common::ApiResult<usize> {
-	debug!("audio_output_data");
-	common::ApiResult::Err(common::Error::Unimplemented)
+/// Queue some samples for playing.
+///
+/// Returns how many bytes were taken, which may be fewer than were given if
+/// the buffer is nearly full. Only whole sample frames are taken.
+unsafe extern "C" fn audio_output_data(samples: common::FfiByteSlice) -> common::ApiResult<usize> {
+	let count = audio::write_output(samples.as_slice());
+	debug!(
+		"audio_output_data({}) -> {}",
+		samples.as_slice().len(),
+		count
+	);
+	common::ApiResult::Ok(count)
 }
 
+/// How many bytes of samples can `audio_output_data` take right now?
 extern "C" fn audio_output_get_space() -> common::ApiResult<usize> {
-	debug!("audio_output_get_space");
-	common::ApiResult::Err(common::Error::Unimplemented)
+	let space = audio::output_space();
+	debug!("audio_output_get_space() -> {}", space);
+	common::ApiResult::Ok(space)
 }
 
-extern "C" fn audio_input_set_config(_config: common::audio::Config) -> common::ApiResult<()> {
-	debug!("audio_input_set_config");
-	common::ApiResult::Err(common::Error::Unimplemented)
+/// Set the sample format and sample rate for audio input.
+///
+/// Recording starts the first time this is called. Any samples that haven't
+/// been read yet are thrown away.
+extern "C" fn audio_input_set_config(config: common::audio::Config) -> common::ApiResult<()> {
+	debug!("audio_input_set_config({:?})", config);
+	audio::set_input_config(config).into()
 }
 
+/// Get the sample format and sample rate for audio input.
 extern "C" fn audio_input_get_config() -> common::ApiResult<common::audio::Config> {
-	debug!("audio_input_get_config");
-	common::ApiResult::Err(common::Error::Unimplemented)
+	debug!("audio_input_get_config()");
+	common::ApiResult::Ok(audio::input_config())
 }
 
-extern "C" fn audio_input_data(_samples: common::FfiBuffer) -> common::ApiResult<usize> {
-	debug!("audio_input_data");
-	common::ApiResult::Err(common::Error::Unimplemented)
+/// Read some recorded samples.
+///
+/// Returns how many bytes were written into the buffer. Only whole sample
+/// frames are given.
+extern "C" fn audio_input_data(mut samples: common::FfiBuffer) -> common::ApiResult<usize> {
+	let Some(buffer) = samples.as_mut_slice() else {
+		return common::ApiResult::Err(common::Error::DeviceError);
+	};
+	let count = audio::read_input(buffer);
+	debug!("audio_input_data({}) -> {}", buffer.len(), count);
+	common::ApiResult::Ok(count)
 }
 
+/// How many bytes of recorded samples are waiting to be read?
 extern "C" fn audio_input_get_count() -> common::ApiResult<usize> {
-	debug!("audio_input_get_count");
-	common::ApiResult::Err(common::Error::Unimplemented)
+	let count = audio::input_count();
+	debug!("audio_input_get_count() -> {}", count);
+	common::ApiResult::Ok(count)
 }
 
 extern "C" fn bus_select(_periperal_id: common::FfiOption<u8>) {
@@ -850,13 +1526,26 @@ extern "C" fn bus_exchange(_buffer: common::FfiBuffer) -> common::ApiResult<()>
 	common::ApiResult::Err(common::Error::Unimplemented)
 }
 
+/// Get the tick count.
+///
+/// With a fixed clock, each call moves the clock on by one tick.
 extern "C" fn time_ticks_get() -> common::Ticks {
-	let mut hw_guard = HARDWARE.lock().unwrap();
-	let hw = hw_guard.as_mut().unwrap();
-	let boot_time = hw.boot_time;
-	let difference = boot_time.elapsed();
-	debug!("time_ticks_get() -> {}", difference.as_millis());
-	common::Ticks(difference.as_millis() as u64)
+	if FIXED_CLOCK.load(Ordering::Relaxed) {
+		FIXED_TICKS.fetch_add(1, Ordering::Relaxed);
+	}
+	let ticks = current_ticks();
+	debug!("time_ticks_get() -> {}", ticks);
+	common::Ticks(ticks)
+}
+
+/// Get the tick count, without moving a fixed clock on.
+fn current_ticks() -> u64 {
+	if FIXED_CLOCK.load(Ordering::Relaxed) {
+		return FIXED_TICKS.load(Ordering::Relaxed);
+	}
+	let hw_guard = HARDWARE.lock().unwrap();
+	let hw = hw_guard.as_ref().unwrap();
+	hw.boot_time.elapsed().as_millis() as u64
 }
 
 /// We simulate a 1 kHz tick
@@ -1015,7 +1704,11 @@ extern "C" fn power_idle() {
 }
 
 extern "C" fn power_control(mode: common::FfiPowerMode) -> ! {
+	#[cfg(unix)]
+	terminal::restore();
 	println!("Got power mode {:?}, but quitting...", mode);
+	stop_recording();
+	audio::stop_wav();
 	std::process::exit(0);
 }
 
@@ -1028,6 +1721,108 @@ extern "C" fn compare_and_swap_bool(
 		.is_ok()
 }
 
+/// Copy a font into font RAM, at the given offset.
+fn load_font_ram(font: &font::Font, offset: usize) {
+	for (idx, byte) in font.data.iter().take(256 * font.height).enumerate() {
+		FONT_RAM.write_at(offset + idx, *byte);
+	}
+}
+
+/// Get a copy of the 8xN glyphs from font RAM.
+///
+/// Only 8x16 and 8x8 fonts are supported.
+fn read_font_ram(font_height: usize) -> Vec<u8> {
+	let offset = if font_height == 16 {
+		FONT_RAM_8X16_OFFSET
+	} else {
+		FONT_RAM_8X8_OFFSET
+	};
+	(0..(256 * font_height))
+		.map(|idx| FONT_RAM.get_at(offset + idx))
+		.collect()
+}
+
+/// Work out the horizontal and vertical scale factors for a video mode.
+///
+/// With aspect correction, the vertical scale is stretched so the mode fills
+/// a 4:3 area, as it would on a real monitor. For `Scale::Fit` and
+/// `Scale::Fill`, pass the size of the display (or `None` if we don't know it
+/// yet, to get 1x).
+fn scale_factors(
+	scale: Scale,
+	aspect_correct: bool,
+	mode: common::video::Mode,
+	display: Option<(u32, u32)>,
+) -> (f32, f32) {
+	let width = f32::from(mode.horizontal_pixels());
+	let height = f32::from(mode.vertical_lines());
+	let aspect = if aspect_correct {
+		(3.0 * width) / (4.0 * height)
+	} else {
+		1.0
+	};
+	let scale_x = match (scale, display) {
+		(Scale::Fixed(n), _) => n as f32,
+		(Scale::Fit | Scale::Fill, Some((display_width, display_height))) => {
+			let fit_x = display_width as f32 / width;
+			let fit_y = display_height as f32 / (height * aspect);
+			let fit = fit_x.min(fit_y);
+			if scale == Scale::Fill {
+				fit
+			} else {
+				fit.floor().max(1.0)
+			}
+		}
+		(Scale::Fit | Scale::Fill, None) => 1.0,
+	};
+	(scale_x, scale_x * aspect)
+}
+
+/// Pack the keyboard LEDs into a byte, so we can keep them in an atomic.
+fn leds_to_bits(leds: common::hid::KeyboardLeds) -> u8 {
+	u8::from(leds.is_caps_lock_on())
+		| (u8::from(leds.is_scroll_lock_on()) << 1)
+		| (u8::from(leds.is_num_lock_on()) << 2)
+}
+
+/// Unpack the keyboard LEDs from a byte made by `leds_to_bits`.
+fn leds_from_bits(bits: u8) -> common::hid::KeyboardLeds {
+	let mut leds = common::hid::KeyboardLeds::new();
+	if bits & 1 != 0 {
+		leds = leds.set_caps_lock_on();
+	}
+	if bits & 2 != 0 {
+		leds = leds.set_scroll_lock_on();
+	}
+	if bits & 4 != 0 {
+		leds = leds.set_num_lock_on();
+	}
+	leds
+}
+
+/// Make a window title which shows the keyboard LEDs.
+fn window_title(leds: common::hid::KeyboardLeds) -> String {
+	let lit: Vec<&str> = [
+		(leds.is_num_lock_on(), "Num"),
+		(leds.is_caps_lock_on(), "Caps"),
+		(leds.is_scroll_lock_on(), "Scroll"),
+	]
+	.iter()
+	.filter(|(on, _)| *on)
+	.map(|(_, name)| *name)
+	.collect();
+	if lit.is_empty() {
+		String::from("Neotron Desktop BIOS")
+	} else {
+		format!("Neotron Desktop BIOS [{}]", lit.join(" "))
+	}
+}
+
+/// Are blinking characters visible on this frame?
+fn text_blink_on() -> bool {
+	(FRAME_COUNT.load(Ordering::Relaxed) & TEXT_BLINK_FRAMES) == 0
+}
+
 // ===========================================================================
 // Impl Blocks
 // ===========================================================================
@@ -1038,14 +1833,22 @@ impl MyApp {
 	/// Generate an RGBA texture for each glyph, in each foreground colour.
 	///
 	/// We have 256 glyphs, in each of 16 colours, so this is expensive and
-	/// slow. But it makes rendering text acceptably fast.
+	/// slow. But it makes rendering text acceptably fast. Glyphs which are
+	/// the same in `previous` (the data the textures were last drawn from)
+	/// are skipped.
 	fn render_font(
 		font: &font::Font,
+		previous: &[u8],
 		texture_buffer: &mut Vec<TextureId>,
 		s: &mut PixState,
 	) -> PixResult<()> {
 		let mut slot = 0;
 		for glyph in 0..=255 {
+			let glyph_rows = (glyph * font.height)..((glyph + 1) * font.height);
+			if previous.get(glyph_rows.clone()) == font.data.get(glyph_rows) {
+				slot += Self::NUM_FG;
+				continue;
+			}
 			for palette_entry in PALETTE.iter().take(Self::NUM_FG) {
 				let fg = RGBColour::from_packed(palette_entry.load(Ordering::Relaxed));
 				debug!(
@@ -1067,8 +1870,7 @@ impl MyApp {
 				s.clear()?;
 				s.stroke(rgb!(fg.red(), fg.green(), fg.blue(), 255));
 				for font_y in 0..(font.height as i32) {
-					let mut font_line =
-						font.data[((glyph as usize) * font.height) + font_y as usize];
+					let mut font_line = font.data[(glyph * font.height) + font_y as usize];
 					for font_x in 0..8i32 {
 						if (font_line & 0x80) != 0 {
 							s.point(Point::new([font_x, font_y]))?;
@@ -1084,20 +1886,60 @@ impl MyApp {
 
 	/// Generate an RGBA texture for each glyph, in each foreground colour, in
 	/// each font.
+	///
+	/// Only the glyphs that have changed in font RAM since last time are drawn.
 	fn render_glyphs(&mut self, s: &mut PixState) -> PixResult<()> {
-		Self::render_font(&font::font16::FONT, &mut self.font8x16, s)?;
-		Self::render_font(&font::font8::FONT, &mut self.font8x8, s)?;
+		let glyphs8x16 = read_font_ram(16);
+		if glyphs8x16 != self.glyphs8x16 {
+			let font = font::Font {
+				name: "8x16 font RAM",
+				height: 16,
+				data: &glyphs8x16,
+			};
+			Self::render_font(&font, &self.glyphs8x16, &mut self.font8x16, s)?;
+			self.glyphs8x16 = glyphs8x16;
+			self.invalidate_screen();
+		}
+		let glyphs8x8 = read_font_ram(8);
+		if glyphs8x8 != self.glyphs8x8 {
+			let font = font::Font {
+				name: "8x8 font RAM",
+				height: 8,
+				data: &glyphs8x8,
+			};
+			Self::render_font(&font, &self.glyphs8x8, &mut self.font8x8, s)?;
+			self.glyphs8x8 = glyphs8x8;
+			self.invalidate_screen();
+		}
 		Ok(())
 	}
 
+	/// Draw a text mode, including blinking characters and the cursor.
+	///
+	/// Only the character cells which have changed since the last frame (as
+	/// recorded in `cache`) are drawn.
 	fn render_text(
 		&self,
+		cache: &mut ScreenCache,
 		font: &[pix_engine::texture::TextureId],
 		font_height: u16,
 		s: &mut PixState,
 	) -> PixResult<()> {
 		let num_cols = self.mode.text_width().unwrap();
 		let num_rows = self.mode.text_height().unwrap();
+		let vram_len = usize::from(num_cols) * usize::from(num_rows) * 2;
+		let full_redraw = cache.vram.len() != vram_len;
+		if full_redraw {
+			cache.vram = vec![0u8; vram_len];
+		}
+		let text_blink_on = text_blink_on();
+		let blink_changed = text_blink_on != cache.text_blink_on;
+		cache.text_blink_on = text_blink_on;
+		let cursor = CURSOR.visible_cell(num_cols, num_rows, font_height);
+		let old_cursor = std::mem::replace(&mut cache.cursor, cursor);
+		let is_cursor_cell = |cell: Option<CursorCell>, col: u16, row: u16| {
+			cell.map(|c| c.col == col && c.row == row).unwrap_or(false)
+		};
 		let mut bg_idx = 0;
 		let mut bg_rgb = {
 			let bg = RGBColour::from_packed(PALETTE[usize::from(bg_idx)].load(Ordering::Relaxed));
@@ -1113,6 +1955,17 @@ impl MyApp {
 				let x = col * 8;
 				let glyph = FRAMEBUFFER.get_at(byte_offset);
 				let attr = common::video::Attr(FRAMEBUFFER.get_at(byte_offset + 1));
+				let dirty = full_redraw
+					|| cache.vram[byte_offset] != glyph
+					|| cache.vram[byte_offset + 1] != attr.as_u8()
+					|| (blink_changed && attr.blink())
+					|| is_cursor_cell(old_cursor, col, row)
+					|| is_cursor_cell(cursor, col, row);
+				if !dirty {
+					continue;
+				}
+				cache.vram[byte_offset] = glyph;
+				cache.vram[byte_offset + 1] = attr.as_u8();
 				let fg_idx = attr.fg().make_ffi_safe().0;
 				let new_bg_idx = attr.bg().make_ffi_safe().0;
 				if new_bg_idx != bg_idx {
@@ -1125,32 +1978,82 @@ impl MyApp {
 				let glyph_box = rect!(i32::from(x), i32::from(y), 8i32, font_height as i32,);
 				s.fill(bg_rgb);
 				s.rect(glyph_box)?;
-				let slot = (usize::from(glyph) * Self::NUM_FG) + usize::from(fg_idx);
-				s.texture(font[slot], None, Some(glyph_box))?;
+				// Blinking characters spend half their time as just background
+				if text_blink_on || !attr.blink() {
+					let slot = (usize::from(glyph) * Self::NUM_FG) + usize::from(fg_idx);
+					s.texture(font[slot], None, Some(glyph_box))?;
+				}
 			}
 		}
+		if let Some(cursor) = cursor {
+			self.render_cursor(cursor, num_cols, font_height, s)?;
+		}
 		Ok(())
 	}
 
-	fn render_chunky<const BPP: usize>(&self, s: &mut PixState) -> PixResult<()> {
+	/// Draw the text cursor over the top of a character cell.
+	fn render_cursor(
+		&self,
+		cursor: CursorCell,
+		num_cols: u16,
+		font_height: u16,
+		s: &mut PixState,
+	) -> PixResult<()> {
+		let byte_offset = usize::from((cursor.row * num_cols) + cursor.col) * 2;
+		let attr = common::video::Attr(FRAMEBUFFER.get_at(byte_offset + 1));
+		let fg_idx = attr.fg().make_ffi_safe().0;
+		let fg = RGBColour::from_packed(PALETTE[usize::from(fg_idx)].load(Ordering::Relaxed));
+		s.stroke(None);
+		s.fill(rgb!(fg.red(), fg.green(), fg.blue()));
+		s.rect(rect!(
+			i32::from(cursor.col * 8),
+			i32::from((cursor.row * font_height) + cursor.start_line),
+			8i32,
+			i32::from(cursor.end_line - cursor.start_line + 1),
+		))?;
+		Ok(())
+	}
+
+	/// Draw a chunky graphics mode.
+	///
+	/// Only the lines which have changed since the last frame (as recorded in
+	/// `cache`) are drawn.
+	fn render_chunky<const BPP: usize>(
+		&self,
+		cache: &mut ScreenCache,
+		s: &mut PixState,
+	) -> PixResult<()> {
 		let shift = 8 - BPP;
 		let num_colours = 1 << BPP;
 		let pixels_per_byte = 8 / BPP;
 		let num_col_bytes = self.mode.line_size_bytes();
 		let num_rows = self.mode.vertical_lines() as usize;
+		let vram_len = num_col_bytes * num_rows;
+		let full_redraw = cache.vram.len() != vram_len;
+		if full_redraw {
+			cache.vram = vec![0u8; vram_len];
+		}
 		let colours = Self::make_colours(num_colours);
+		let mut line = vec![0u8; num_col_bytes];
 		for y in 0..num_rows {
 			let y_bytes = y * num_col_bytes;
-			for x_byte in 0..num_col_bytes {
-				let byte_offset = y_bytes + x_byte;
-				let mut data = FRAMEBUFFER.get_at(byte_offset);
+			for (x_byte, byte) in line.iter_mut().enumerate() {
+				*byte = FRAMEBUFFER.get_at(y_bytes + x_byte);
+			}
+			let shadow = &mut cache.vram[y_bytes..y_bytes + num_col_bytes];
+			if !full_redraw && shadow == line.as_slice() {
+				continue;
+			}
+			shadow.copy_from_slice(&line);
+			for (x_byte, data) in line.iter().enumerate() {
+				let mut data = *data;
 				let x_start = x_byte * pixels_per_byte;
 				for x in 0..pixels_per_byte {
 					let bit = (data >> shift) as usize;
 					s.stroke(colours[bit]);
 					let p = point!((x_start + x) as i32, y as i32);
 					s.point(p)?;
-					data <<= BPP;
+					data = data.wrapping_shl(BPP as u32);
 				}
 			}
 		}
@@ -1169,41 +2072,381 @@ impl MyApp {
 		}
 		result
 	}
+
+	/// Act on a host hotkey (a key pressed while holding Ctrl+Alt).
+	///
+	/// Returns `true` if the key was one of ours, in which case it is not
+	/// passed on to the OS.
+	fn handle_hotkey(&mut self, key: Option<Key>, s: &mut PixState) -> PixResult<bool> {
+		let Some(key) = key else {
+			return Ok(false);
+		};
+		match key {
+			Key::S => {
+				save_screenshot(&screenshot_path("png"));
+				Ok(true)
+			}
+			Key::R => {
+				if RECORDER.lock().unwrap().is_some() {
+					stop_recording();
+				} else {
+					start_recording(&screenshot_path("y4m"));
+				}
+				Ok(true)
+			}
+			Key::P => {
+				save_palette(&screenshot_path(self.palette_format.extension()));
+				Ok(true)
+			}
+			Key::D => {
+				self.overlay = !self.overlay;
+				Ok(true)
+			}
+			Key::V => {
+				// The OS thinks Ctrl and Alt are held down, so we wait until
+				// they are released before we start typing.
+				self.paste_pending = Some(s.clipboard_text());
+				Ok(true)
+			}
+			Key::C => {
+				self.crt = !self.crt;
+				info!("CRT effects {}", self.crt);
+				Ok(true)
+			}
+			Key::F => {
+				self.fullscreen = !self.fullscreen;
+				info!("Fullscreen {}", self.fullscreen);
+				s.fullscreen(self.fullscreen)?;
+				// Re-do the scaling, as the screen size may have changed
+				self.reset = true;
+				Ok(true)
+			}
+			_ => Ok(false),
+		}
+	}
+
+	/// Draw video RAM straight onto the window, using the GPU where we can.
+	///
+	/// We draw into a texture which we keep from one frame to the next, only
+	/// redrawing what has changed, and then copy that texture to the window.
+	fn render_direct(&mut self, s: &mut PixState) -> PixResult<()> {
+		let mut cache = match self.screen.take() {
+			Some(cache) if cache.mode == self.mode => cache,
+			old => {
+				if let Some(old) = old {
+					s.delete_texture(old.texture)?;
+				}
+				ScreenCache::new(self.mode, s)?
+			}
+		};
+		let palette: Vec<u32> = PALETTE
+			.iter()
+			.map(|entry| entry.load(Ordering::Relaxed))
+			.collect();
+		if palette != cache.palette {
+			cache.palette = palette;
+			cache.vram.clear();
+		}
+
+		s.set_texture_target(cache.texture)?;
+		let result = match self.mode.format() {
+			common::video::Format::Text8x16 => self.render_text(&mut cache, &self.font8x16, 16, s),
+			common::video::Format::Text8x8 => self.render_text(&mut cache, &self.font8x8, 8, s),
+			common::video::Format::Chunky1 => self.render_chunky::<1>(&mut cache, s),
+			common::video::Format::Chunky2 => self.render_chunky::<2>(&mut cache, s),
+			common::video::Format::Chunky4 => self.render_chunky::<4>(&mut cache, s),
+			common::video::Format::Chunky8 => self.render_chunky::<8>(&mut cache, s),
+			_ => {
+				// Unknown mode - do nothing
+				Ok(())
+			}
+		};
+		s.clear_texture_target();
+		result?;
+
+		let dest = rect!(
+			0,
+			0,
+			i32::from(self.mode.horizontal_pixels()),
+			i32::from(self.mode.vertical_lines()),
+		);
+		s.texture(cache.texture, None, Some(dest))?;
+		self.screen = Some(cache);
+		Ok(())
+	}
+
+	/// Tell the OS the mouse moved (in video pixels), along with the current
+	/// state of the buttons.
+	fn send_mouse(&self, dx: i16, dy: i16) {
+		send_event(
+			&self.sender,
+			AppEvent::Mouse {
+				dx,
+				dy,
+				left: self.mouse.left,
+				middle: self.mouse.middle,
+				right: self.mouse.right,
+			},
+		);
+	}
+
+	/// Re-open the audio device if the OS has changed the output format.
+	///
+	/// If we can't open one, we don't try again until the format changes.
+	fn update_playback(&mut self, s: &mut PixState) {
+		let generation = audio::output_generation();
+		if self.playback_generation == Some(generation) || !audio::playback_enabled() {
+			return;
+		}
+		self.playback_generation = Some(generation);
+		// Close the old device first, so SDL can give us the same one back
+		self.playback = None;
+		match audio::open_playback(s) {
+			Ok(device) => {
+				device.resume();
+				self.playback = Some(device);
+			}
+			Err(e) => log::warn!("Failed to open audio playback: {:?}", e),
+		}
+	}
+
+	/// Re-open the microphone if the OS has changed the input format.
+	///
+	/// We don't open it at all until the OS sets an input format, and if we
+	/// can't open it, we don't try again until the format changes.
+	fn update_capture(&mut self, s: &mut PixState) {
+		let generation = audio::input_generation();
+		if self.capture_generation == generation || !audio::capture_enabled() {
+			return;
+		}
+		self.capture_generation = generation;
+		self.capture = None;
+		match audio::open_capture(s) {
+			Ok(device) => {
+				device.resume();
+				self.capture = Some(device);
+			}
+			Err(e) => log::warn!("Failed to open audio capture: {:?}", e),
+		}
+	}
+
+	/// Tap Caps Lock and/or Num Lock, if the OS's LEDs don't match the host's
+	/// lock keys.
+	///
+	/// pix-engine doesn't tell us about the lock keys, so we ask SDL.
+	fn sync_lock_keys(&self) {
+		let host =
+			sdl2::keyboard::Mod::from_bits_truncate(unsafe { sdl2::sys::SDL_GetModState() } as u16);
+		let leds = leds_from_bits(KEYBOARD_LEDS.load(Ordering::Relaxed));
+		let locks = [
+			(
+				host.contains(sdl2::keyboard::Mod::CAPSMOD),
+				leds.is_caps_lock_on(),
+				common::hid::KeyCode::CapsLock,
+			),
+			(
+				host.contains(sdl2::keyboard::Mod::NUMMOD),
+				leds.is_num_lock_on(),
+				common::hid::KeyCode::NumpadLock,
+			),
+		];
+		for (host_on, os_on, key) in locks {
+			if host_on != os_on {
+				info!("Tapping {:?} to match the host", key);
+				send_event(&self.sender, AppEvent::KeyDown(key));
+				send_event(&self.sender, AppEvent::KeyUp(key));
+			}
+		}
+	}
+
+	/// Make the next frame redraw everything from scratch.
+	fn invalidate_screen(&mut self) {
+		if let Some(cache) = self.screen.as_mut() {
+			cache.vram.clear();
+		}
+	}
+
+	/// Draw video RAM in software, apply the CRT effects, and then put the
+	/// result on the window.
+	///
+	/// The effects are slow, so if nothing has changed since last time, we
+	/// just draw the texture we made then.
+	fn render_crt(&mut self, s: &mut PixState) -> PixResult<()> {
+		let source = (
+			render::Frame::render(self.mode),
+			self.video_scale,
+			self.crt_effects,
+		);
+		let texture_id = match self.crt_texture {
+			Some((id, _, _)) if self.crt_source.as_ref() == Some(&source) => id,
+			old => {
+				let frame = crt::apply(&source.0, source.1, source.2);
+				let id = match old {
+					Some((id, width, height)) if width == frame.width && height == frame.height => {
+						id
+					}
+					old => {
+						if let Some((id, _, _)) = old {
+							s.delete_texture(id)?;
+						}
+						let id = s.create_texture(
+							frame.width as u32,
+							frame.height as u32,
+							PixelFormat::Rgb,
+						)?;
+						self.crt_texture = Some((id, frame.width, frame.height));
+						id
+					}
+				};
+				s.update_texture(id, None, &frame.pixels, frame.width * 3)?;
+				self.crt_source = Some(source);
+				id
+			}
+		};
+		// The frame is oversampled, so squash it back down to the size of the
+		// video mode, and let the window scaling blow it up again.
+		let dest = rect!(
+			0,
+			0,
+			i32::from(self.mode.horizontal_pixels()),
+			i32::from(self.mode.vertical_lines()),
+		);
+		s.texture(texture_id, None, Some(dest))
+	}
 }
 
 impl PixEngine for MyApp {
 	/// Perform application initialisation.
 	fn on_start(&mut self, s: &mut PixState) -> PixResult<()> {
+		keymap::watch_raw_keys();
 		self.render_glyphs(s)?;
 		// Let the rest of the OS start now
 		self.sender.send(AppEvent::Started).unwrap();
+		if self.sync_lock_keys {
+			self.sync_lock_keys();
+		}
 		Ok(())
 	}
 
 	/// Terminate the process to ensure the OS thread dies too.
 	fn on_stop(&mut self, _s: &mut PixState) -> PixResult<()> {
+		stop_recording();
+		audio::stop_wav();
 		std::process::exit(0);
 	}
 
 	/// Called whenever the app has an event to process.
 	///
 	/// We send key up and key down events into a queue for the OS to process later.
-	fn on_event(&mut self, _s: &mut PixState, event: &Event) -> PixResult<bool> {
+	fn on_event(&mut self, s: &mut PixState, event: &Event) -> PixResult<bool> {
 		match event {
 			Event::KeyUp {
-				key: Some(key),
-				keymod: _,
+				key,
+				keymod,
 				repeat: _,
 			} => {
-				self.sender.send(AppEvent::KeyUp(*key)).unwrap();
+				// Always convert the key, to keep the keymap in step with SDL
+				let code = keymap::from_key_event(*key, false);
+				if key.is_some() && self.hotkey_held == *key {
+					self.hotkey_held = None;
+				} else if let Some(code) = code {
+					send_event(&self.sender, AppEvent::KeyUp(code));
+				}
+				if !keymod.intersects(KeyMod::CTRL | KeyMod::ALT) {
+					if let Some(text) = self.paste_pending.take() {
+						paste::spawn(text, self.paste_rate, self.sender.clone());
+					}
+				}
 				Ok(true)
 			}
 			Event::KeyDown {
-				key: Some(key),
-				keymod: _,
-				repeat: _,
+				key,
+				keymod,
+				repeat,
 			} => {
-				self.sender.send(AppEvent::KeyDown(*key)).unwrap();
+				let code = keymap::from_key_event(*key, true);
+				if key.is_some() && self.hotkey_held == *key {
+					// Holding a hotkey down doesn't do it again
+					debug!("Ignoring auto-repeat of hotkey {:?}", key);
+				} else if *repeat && self.drop_key_repeats {
+					debug!("Dropping host auto-repeat of {:?}", key);
+				} else if !*repeat
+					&& keymod.contains(KeyMod::CTRL | KeyMod::ALT)
+					&& self.handle_hotkey(*key, s)?
+				{
+					self.hotkey_held = *key;
+				} else if let Some(code) = code {
+					send_event(&self.sender, AppEvent::KeyDown(code));
+				} else {
+					log::warn!("Dropping {:?}, which the Neotron doesn't have", key);
+				}
+				Ok(true)
+			}
+			Event::MouseMotion { xrel, yrel, .. } => {
+				// Window pixels to video pixels, keeping any fractions for next time
+				let (rem_x, rem_y) = self.mouse.remainder;
+				let x = (*xrel as f32 / self.video_scale.0) + rem_x;
+				let y = (*yrel as f32 / self.video_scale.1) + rem_y;
+				let (dx, dy) = (x.trunc(), y.trunc());
+				self.mouse.remainder = (x - dx, y - dy);
+				if dx != 0.0 || dy != 0.0 {
+					self.send_mouse(dx as i16, dy as i16);
+				}
+				Ok(true)
+			}
+			Event::MouseDown { button, .. } | Event::MouseUp { button, .. } => {
+				let pressed = matches!(event, Event::MouseDown { .. });
+				match button {
+					Mouse::Left => self.mouse.left = pressed,
+					Mouse::Middle => self.mouse.middle = pressed,
+					Mouse::Right => self.mouse.right = pressed,
+					_ => {
+						debug!("Ignoring mouse button {:?}", button);
+						return Ok(false);
+					}
+				}
+				self.send_mouse(0, 0);
+				Ok(true)
+			}
+			Event::MouseWheel { .. } => {
+				debug!("The OS can't be told about the mouse wheel: {:?}", event);
+				Ok(false)
+			}
+			Event::ControllerAdded { controller_id } => {
+				info!("Game controller {} connected", controller_id);
+				// pix-engine opens it for us
+				Ok(false)
+			}
+			Event::ControllerRemoved { controller_id } => {
+				info!("Game controller {} disconnected", controller_id);
+				for ev in self.gamepads.removed(*controller_id) {
+					send_event(&self.sender, ev);
+				}
+				// pix-engine closes it for us
+				Ok(false)
+			}
+			Event::ControllerDown {
+				controller_id,
+				button,
+			}
+			| Event::ControllerUp {
+				controller_id,
+				button,
+			} => {
+				let pressed = matches!(event, Event::ControllerDown { .. });
+				for ev in self.gamepads.button(*controller_id, *button, pressed) {
+					send_event(&self.sender, ev);
+				}
+				Ok(true)
+			}
+			Event::ControllerAxisMotion {
+				controller_id,
+				axis,
+				value,
+			} => {
+				for ev in self.gamepads.axis_motion(*controller_id, *axis, *value) {
+					send_event(&self.sender, ev);
+				}
 				Ok(true)
 			}
 			Event::Window {
@@ -1214,6 +2457,34 @@ impl PixEngine for MyApp {
 				self.reset = true;
 				Ok(true)
 			}
+			Event::Window {
+				win_event: WindowEvent::FocusGained,
+				..
+			} => {
+				// The lock keys may have changed while we weren't looking
+				if self.sync_lock_keys {
+					self.sync_lock_keys();
+				}
+				// pix-engine needs to see this too
+				Ok(false)
+			}
+			Event::Window {
+				win_event: WindowEvent::FocusLost,
+				..
+			} => {
+				// pix-engine may not pass on the key events that were waiting
+				keymap::forget_raw_keys();
+				// pix-engine needs to see this too
+				Ok(false)
+			}
+			Event::Window {
+				win_event: WindowEvent::Resized(_, _),
+				..
+			} => {
+				// Keep the image in the middle of the window
+				self.reset = true;
+				Ok(true)
+			}
 			_ => {
 				debug!("Didn't know about {:?}", event);
 				Ok(false)
@@ -1225,39 +2496,128 @@ impl PixEngine for MyApp {
 	///
 	/// We convert the contents of `FRAMEBUFFER` into pixels on the canvas.
 	fn on_update(&mut self, s: &mut PixState) -> PixResult<()> {
+		while let Ok(command) = self.commands.try_recv() {
+			run_command(command);
+		}
+
+		self.update_playback(s);
+		self.update_capture(s);
+
 		let mode_value = VIDEO_MODE.load(Ordering::Relaxed);
 		let new_mode = unsafe { common::video::Mode::from_u8(mode_value) };
 		if new_mode != self.mode || self.reset {
 			info!("New video mode detected, or needs reset");
 			self.reset = false;
 			self.mode = new_mode;
-			let width = (new_mode.horizontal_pixels() as f32) * SCALE_FACTOR;
-			let height = (new_mode.vertical_lines() as f32) * SCALE_FACTOR;
-			info!("Window set to {} x {}", width, height);
-			s.set_window_dimensions((width as u32, height as u32))?;
-			s.scale(SCALE_FACTOR, SCALE_FACTOR)?;
+			// A fixed scale might not fit in fullscreen, so we use the
+			// biggest whole number that does.
+			let scale = match self.scale {
+				Scale::Fixed(_) if self.fullscreen => Scale::Fit,
+				other => other,
+			};
+			let display = match scale {
+				Scale::Fixed(_) => None,
+				_ if self.fullscreen => Some(s.window_dimensions()?),
+				_ => Some(s.display_dimensions()?),
+			};
+			let (scale_x, scale_y) = scale_factors(scale, self.aspect_correct, new_mode, display);
+			let width = (new_mode.horizontal_pixels() as f32) * scale_x;
+			let height = (new_mode.vertical_lines() as f32) * scale_y;
+			if !self.fullscreen {
+				info!("Window set to {} x {}", width, height);
+				s.set_window_dimensions((width as u32, height as u32))?;
+			}
+			s.scale(scale_x, scale_y)?;
+			self.video_scale = (scale_x, scale_y);
+			// Put the image in the middle of the window. The viewport is given
+			// in video pixels, as SDL scales it for us.
+			let (window_width, window_height) = s.window_dimensions()?;
+			let offset_x = ((window_width as f32 - width) / 2.0).max(0.0).floor();
+			let offset_y = ((window_height as f32 - height) / 2.0).max(0.0).floor();
+			self.video_offset = (offset_x, offset_y);
+			s.set_viewport(rect!(
+				(offset_x / scale_x) as i32,
+				(offset_y / scale_y) as i32,
+				i32::from(new_mode.horizontal_pixels()),
+				i32::from(new_mode.vertical_lines()),
+			))?;
 			s.background(rgb!(0, 0, 0));
 			s.clear()?;
+			self.invalidate_screen();
+		}
+
+		let leds = KEYBOARD_LEDS.load(Ordering::Relaxed);
+		if self.leds_shown != Some(leds) {
+			s.set_title(window_title(leds_from_bits(leds)))?;
+			self.leds_shown = Some(leds);
 		}
 
+		self.render_glyphs(s)?;
+
 		s.blend_mode(BlendMode::Blend);
 
-		match self.mode.format() {
-			common::video::Format::Text8x16 => self.render_text(&self.font8x16, 16, s)?,
-			common::video::Format::Text8x8 => self.render_text(&self.font8x8, 8, s)?,
-			common::video::Format::Chunky1 => self.render_chunky::<1>(s)?,
-			common::video::Format::Chunky2 => self.render_chunky::<2>(s)?,
-			common::video::Format::Chunky4 => self.render_chunky::<4>(s)?,
-			common::video::Format::Chunky8 => self.render_chunky::<8>(s)?,
-			_ => {
-				// Unknown mode - do nothing
-			}
+		if self.crt {
+			self.render_crt(s)?;
+		} else {
+			self.render_direct(s)?;
+		}
+
+		if self.overlay {
+			// SDL gives us the mouse position in window pixels, so undo the
+			// centring and scaling to get video pixels
+			let mouse = s.mouse_pos();
+			let mouse = point!(
+				((mouse.x() as f32 - self.video_offset.0) / self.video_scale.0) as i32,
+				((mouse.y() as f32 - self.video_offset.1) / self.video_scale.1) as i32,
+			);
+			overlay::draw(s, self.mode, mouse, self.video_scale)?;
 		}
 
+		FRAME_COUNT.fetch_add(1, Ordering::Relaxed);
+		record_frame();
+
 		Ok(())
 	}
 }
 
+impl ScreenCache {
+	/// Make a texture for the given video mode.
+	///
+	/// The first frame drawn into it will be a full redraw.
+	fn new(mode: common::video::Mode, s: &mut PixState) -> PixResult<ScreenCache> {
+		let texture = s.create_texture(
+			u32::from(mode.horizontal_pixels()),
+			u32::from(mode.vertical_lines()),
+			PixelFormat::Rgba,
+		)?;
+		Ok(ScreenCache {
+			texture,
+			mode,
+			vram: Vec::new(),
+			palette: Vec::new(),
+			text_blink_on: true,
+			cursor: None,
+		})
+	}
+}
+
+impl std::str::FromStr for Scale {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Scale, String> {
+		if s.eq_ignore_ascii_case("fit") {
+			return Ok(Scale::Fit);
+		}
+		if s.eq_ignore_ascii_case("fill") {
+			return Ok(Scale::Fill);
+		}
+		match s.trim_end_matches(['x', 'X']).parse::<u32>() {
+			Ok(n) if n > 0 => Ok(Scale::Fixed(n)),
+			_ => Err(format!("{:?} is not a whole number, 'fit' or 'fill'", s)),
+		}
+	}
+}
+
 impl<const N: usize> Framebuffer<N> {
 	/// Create a new blank Framebuffer.
 	///
@@ -1295,6 +2655,11 @@ impl<const N: usize> Framebuffer<N> {
 		}
 	}
 
+	/// Get the size of the framebuffer, in bytes.
+	const fn len(&self) -> usize {
+		N
+	}
+
 	/// Get a pointer to the framebuffer you can give to the OS.
 	fn get_pointer(&self) -> *mut u32 {
 		let mut p = self.alt_pointer.load(Ordering::Relaxed);
@@ -1307,6 +2672,67 @@ impl<const N: usize> Framebuffer<N> {
 
 unsafe impl<const N: usize> Sync for Framebuffer<N> {}
 
+impl CursorRegisters {
+	/// Bit in the control register that makes the cursor visible
+	const CONTROL_VISIBLE: u8 = 1 << 0;
+	/// Bit in the control register that makes the cursor blink
+	const CONTROL_BLINK: u8 = 1 << 1;
+
+	/// Create a new set of cursor registers.
+	///
+	/// The cursor starts hidden, in the top-left corner, as an underline.
+	const fn new() -> CursorRegisters {
+		CursorRegisters {
+			col: AtomicU8::new(0),
+			row: AtomicU8::new(0),
+			start_line: AtomicU8::new(14),
+			end_line: AtomicU8::new(15),
+			control: AtomicU8::new(0),
+		}
+	}
+
+	/// Get the cursor's (column, row), if it is turned on and on-screen.
+	///
+	/// Ignores the blink setting.
+	fn position(&self, num_cols: u16, num_rows: u16) -> Option<(u16, u16)> {
+		let control = self.control.load(Ordering::Relaxed);
+		if (control & Self::CONTROL_VISIBLE) == 0 {
+			return None;
+		}
+		let col = u16::from(self.col.load(Ordering::Relaxed));
+		let row = u16::from(self.row.load(Ordering::Relaxed));
+		if col >= num_cols || row >= num_rows {
+			return None;
+		}
+		Some((col, row))
+	}
+
+	/// Work out where the cursor should be drawn on this frame.
+	///
+	/// Returns `None` if the cursor is hidden, is in the 'off' half of its
+	/// blink, or has been parked off-screen.
+	fn visible_cell(&self, num_cols: u16, num_rows: u16, font_height: u16) -> Option<CursorCell> {
+		let (col, row) = self.position(num_cols, num_rows)?;
+		let control = self.control.load(Ordering::Relaxed);
+		let cursor_blink_on = (FRAME_COUNT.load(Ordering::Relaxed) & CURSOR_BLINK_FRAMES) == 0;
+		if (control & Self::CONTROL_BLINK) != 0 && !cursor_blink_on {
+			return None;
+		}
+		let last_line = font_height - 1;
+		let start_line = u16::from(self.start_line.load(Ordering::Relaxed)).min(last_line);
+		let end_line = u16::from(self.end_line.load(Ordering::Relaxed)).min(last_line);
+		if end_line < start_line {
+			return None;
+		}
+		Some(CursorCell {
+			col,
+			row,
+			start_line,
+			end_line,
+		})
+	}
+}
+
 // ===========================================================================
 // End of File
 // ===========================================================================
@@ -0,0 +1,221 @@
+//! # Debug overlay for the Neotron Desktop BIOS
+//!
+//! Draws the palette, the current video mode, where the framebuffer is, and a
+//! hex dump of video RAM under the mouse pointer, on top of the display.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::sync::atomic::Ordering;
+
+use pix_engine::prelude::*;
+
+use crate::common;
+use common::video::RGBColour;
+
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// The size of each palette swatch, in video pixels
+const SWATCH_SIZE: i32 = 5;
+
+/// The gap around the edge of the panel, and between its parts
+const MARGIN: i32 = 4;
+
+/// How tall each line of text is
+const LINE_HEIGHT: i32 = 10;
+
+/// The font size for the text
+const FONT_SIZE: u32 = 8;
+
+/// How many rows of 16 bytes we show in the hex view
+const HEX_ROWS: usize = 8;
+
+/// How wide the panel is. In modes narrower than this, the whole panel is
+/// drawn smaller so it fits.
+const PANEL_WIDTH: i32 = 470;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Draw the debug overlay.
+///
+/// The mouse position is in video pixels, and `video_scale` is the window
+/// scale the display is drawn with. The panel goes on whichever side of the
+/// screen the mouse isn't on.
+pub fn draw(
+	s: &mut PixState,
+	mode: common::video::Mode,
+	mouse: Point,
+	video_scale: (f32, f32),
+) -> PixResult<()> {
+	let mouse_x = usize::try_from(mouse.x()).unwrap_or(0);
+	let mouse_y = usize::try_from(mouse.y()).unwrap_or(0);
+	let mut lines = vec![
+		format!(
+			"Mode {}: {:?}, {}x{}",
+			mode.as_u8(),
+			mode.format(),
+			mode.horizontal_pixels(),
+			mode.vertical_lines()
+		),
+		match (mode.text_width(), mode.text_height()) {
+			(Some(cols), Some(rows)) => format!("{} cols x {} rows", cols, rows),
+			_ => format!("{} bytes per line", mode.line_size_bytes()),
+		},
+		format!(
+			"Framebuffer {:p} ({})",
+			crate::FRAMEBUFFER.get_pointer(),
+			if crate::FRAMEBUFFER
+				.alt_pointer
+				.load(Ordering::Relaxed)
+				.is_null()
+			{
+				"internal"
+			} else {
+				"supplied by OS"
+			}
+		),
+		format!(
+			"Key presses dropped: {}",
+			crate::HID_OVERFLOWS.load(Ordering::Relaxed)
+		),
+	];
+	let offset = vram_offset(mode, mouse_x, mouse_y);
+	match offset {
+		Some(offset) => {
+			lines.push(format!(
+				"Mouse {},{} is VRAM offset 0x{:05x}",
+				mouse_x, mouse_y, offset
+			));
+			lines.extend(hex_view(mode, offset));
+		}
+		None => lines.push(String::from("Mouse is off the screen")),
+	}
+
+	let panel_height = (MARGIN * 3) + (SWATCH_SIZE * 16) + (LINE_HEIGHT * lines.len() as i32);
+	let mouse_on_left = mouse.x() < i32::from(mode.horizontal_pixels()) / 2;
+	// Shrink everything if the panel won't fit across the screen, and work in
+	// the shrunken coordinates from here on
+	let shrink =
+		(f32::from(mode.horizontal_pixels()) / (PANEL_WIDTH + (MARGIN * 2)) as f32).min(1.0);
+	s.scale(video_scale.0 * shrink, video_scale.1 * shrink)?;
+	let screen_width = (f32::from(mode.horizontal_pixels()) / shrink) as i32;
+	let panel_x = if mouse_on_left {
+		screen_width - PANEL_WIDTH - MARGIN
+	} else {
+		MARGIN
+	};
+	let panel_y = MARGIN;
+
+	s.stroke(None);
+	s.fill(rgb!(0, 0, 0, 208));
+	s.rect(rect!(panel_x, panel_y, PANEL_WIDTH, panel_height))?;
+
+	for (idx, entry) in crate::PALETTE.iter().enumerate() {
+		let colour = RGBColour::from_packed(entry.load(Ordering::Relaxed));
+		let idx = idx as i32;
+		s.fill(rgb!(colour.red(), colour.green(), colour.blue()));
+		s.rect(rect!(
+			panel_x + MARGIN + ((idx % 16) * SWATCH_SIZE),
+			panel_y + MARGIN + ((idx / 16) * SWATCH_SIZE),
+			SWATCH_SIZE,
+			SWATCH_SIZE
+		))?;
+	}
+
+	s.font_size(FONT_SIZE)?;
+	s.fill(rgb!(255, 255, 255));
+	let text_y = panel_y + (MARGIN * 2) + (SWATCH_SIZE * 16);
+	for (idx, line) in lines.iter().enumerate() {
+		s.set_cursor_pos(point!(
+			panel_x + MARGIN,
+			text_y + (idx as i32 * LINE_HEIGHT)
+		));
+		s.text(line)?;
+	}
+	s.scale(video_scale.0, video_scale.1)
+}
+
+/// Work out which byte of video RAM holds the given pixel.
+///
+/// For text modes, this is the glyph byte of the character cell.
+fn vram_offset(mode: common::video::Mode, x: usize, y: usize) -> Option<usize> {
+	if x >= usize::from(mode.horizontal_pixels()) || y >= usize::from(mode.vertical_lines()) {
+		return None;
+	}
+	let bits_per_pixel = match mode.format() {
+		common::video::Format::Text8x16 | common::video::Format::Text8x8 => {
+			let font_height = if mode.format() == common::video::Format::Text8x16 {
+				16
+			} else {
+				8
+			};
+			let num_cols = usize::from(mode.text_width()?);
+			return Some((((y / font_height) * num_cols) + (x / 8)) * 2);
+		}
+		common::video::Format::Chunky1 => 1,
+		common::video::Format::Chunky2 => 2,
+		common::video::Format::Chunky4 => 4,
+		common::video::Format::Chunky8 => 8,
+		_ => 16,
+	};
+	Some((y * mode.line_size_bytes()) + ((x * bits_per_pixel) / 8))
+}
+
+/// Dump the bytes of video RAM around the given offset, 16 to a line.
+///
+/// The byte at `offset` is marked with square brackets.
+fn hex_view(mode: common::video::Mode, offset: usize) -> Vec<String> {
+	let vram_len = mode.frame_size_bytes();
+	let last_row = vram_len.saturating_sub(1) & !0xF;
+	let start = (offset & !0xF)
+		.saturating_sub((HEX_ROWS / 2) * 16)
+		.min(last_row.saturating_sub((HEX_ROWS - 1) * 16));
+	let mut lines = Vec::new();
+	for row_start in (start..=last_row).step_by(16).take(HEX_ROWS) {
+		let mut line = format!("{:05x}:", row_start);
+		for addr in row_start..(row_start + 16).min(vram_len) {
+			let separator = if addr == offset {
+				'['
+			} else if addr == offset + 1 && addr != row_start {
+				']'
+			} else {
+				' '
+			};
+			line.push(separator);
+			line.push_str(&format!("{:02x}", crate::FRAMEBUFFER.get_at(addr)));
+		}
+		if offset == row_start + 15 {
+			line.push(']');
+		}
+		lines.push(line);
+	}
+	lines
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
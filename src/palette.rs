@@ -1,6 +1,18 @@
 use neotron_common_bios::video::RGBColour;
+use std::path::Path;
 use std::sync::atomic::AtomicU32;
 
+/// The palette file formats we can read and write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+	/// A GIMP palette (`.gpl`)
+	Gimp,
+	/// A JASC (Paint Shop Pro) palette (`.pal`)
+	Jasc,
+	/// One `#RRGGBB` colour per line (`.hex`)
+	Hex,
+}
+
 pub(crate) const fn make_default_palette() -> [std::sync::atomic::AtomicU32; 256] {
 	[
 		// Index 000: 0x000 (Black)
@@ -517,3 +529,225 @@ pub(crate) const fn make_default_palette() -> [std::sync::atomic::AtomicU32; 256
 		AtomicU32::new(RGBColour::from_rgb(0x77, 0x77, 0x77).as_packed()),
 	]
 }
+
+/// Pick a palette format based on a file's extension.
+///
+/// Anything we don't recognise is a hex list.
+pub(crate) fn format_for_path(path: &Path) -> Format {
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some(ext) if ext.eq_ignore_ascii_case("gpl") => Format::Gimp,
+		Some(ext) if ext.eq_ignore_ascii_case("pal") => Format::Jasc,
+		_ => Format::Hex,
+	}
+}
+
+impl Format {
+	/// The file extension for this format.
+	pub(crate) fn extension(self) -> &'static str {
+		match self {
+			Format::Gimp => "gpl",
+			Format::Jasc => "pal",
+			Format::Hex => "hex",
+		}
+	}
+}
+
+impl std::str::FromStr for Format {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Format, String> {
+		match s.to_ascii_lowercase().as_str() {
+			"gpl" | "gimp" => Ok(Format::Gimp),
+			"pal" | "jasc" => Ok(Format::Jasc),
+			"hex" => Ok(Format::Hex),
+			_ => Err(format!("{:?} is not 'gpl', 'pal' or 'hex'", s)),
+		}
+	}
+}
+
+/// Load a palette from a GIMP, JASC or hex-list file.
+///
+/// The format is worked out from the contents of the file.
+pub(crate) fn load(path: &Path) -> std::io::Result<Vec<RGBColour>> {
+	let text = std::fs::read_to_string(path)?;
+	parse(&text).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Save a palette to a file, in the format given by the file's extension.
+pub(crate) fn save(path: &Path, colours: &[RGBColour]) -> std::io::Result<()> {
+	std::fs::write(path, to_string(colours, format_for_path(path)))
+}
+
+/// Parse a GIMP, JASC or hex-list palette.
+pub(crate) fn parse(text: &str) -> Result<Vec<RGBColour>, String> {
+	let mut lines = text.lines().enumerate();
+	let format = match text.lines().next().map(str::trim) {
+		Some("GIMP Palette") => Format::Gimp,
+		Some("JASC-PAL") => Format::Jasc,
+		_ => Format::Hex,
+	};
+	match format {
+		Format::Gimp => {
+			// Skip the magic line
+			lines.next();
+		}
+		Format::Jasc => {
+			// Skip the magic line, the version and the colour count
+			lines.next();
+			lines.next();
+			lines.next();
+		}
+		Format::Hex => {}
+	}
+	let mut colours = Vec::new();
+	for (line_idx, line) in lines {
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+		let colour = match format {
+			Format::Gimp => {
+				if line.starts_with('#')
+					|| line.starts_with("Name:")
+					|| line.starts_with("Columns:")
+				{
+					// A comment, or a header
+					continue;
+				}
+				parse_decimal(line)
+			}
+			Format::Jasc => parse_decimal(line),
+			Format::Hex => {
+				if line.starts_with(';') || line.starts_with("//") {
+					continue;
+				}
+				parse_hex(line)
+			}
+		};
+		match colour {
+			Some(colour) => colours.push(colour),
+			None => return Err(format!("bad colour on line {}: {:?}", line_idx + 1, line)),
+		}
+	}
+	if colours.is_empty() {
+		return Err(String::from("no colours found"));
+	}
+	Ok(colours)
+}
+
+/// Write a palette out in the given format.
+pub(crate) fn to_string(colours: &[RGBColour], format: Format) -> String {
+	let mut result = match format {
+		Format::Gimp => String::from("GIMP Palette\nName: Neotron\nColumns: 16\n#\n"),
+		Format::Jasc => format!("JASC-PAL\n0100\n{}\n", colours.len()),
+		Format::Hex => String::new(),
+	};
+	for (idx, colour) in colours.iter().enumerate() {
+		let (r, g, b) = (colour.red(), colour.green(), colour.blue());
+		let line = match format {
+			Format::Gimp => format!("{:3} {:3} {:3}\tIndex {}\n", r, g, b, idx),
+			Format::Jasc => format!("{} {} {}\n", r, g, b),
+			Format::Hex => format!("#{:02x}{:02x}{:02x}\n", r, g, b),
+		};
+		result.push_str(&line);
+	}
+	result
+}
+
+/// Parse three decimal numbers (and maybe a name) as a colour.
+fn parse_decimal(line: &str) -> Option<RGBColour> {
+	let mut words = line.split_whitespace();
+	let r = words.next()?.parse().ok()?;
+	let g = words.next()?.parse().ok()?;
+	let b = words.next()?.parse().ok()?;
+	Some(RGBColour::from_rgb(r, g, b))
+}
+
+/// Parse a colour like `#RRGGBB`, `0xRRGGBB` or `RRGGBB`.
+fn parse_hex(line: &str) -> Option<RGBColour> {
+	let digits = line
+		.strip_prefix('#')
+		.or_else(|| line.strip_prefix("0x"))
+		.unwrap_or(line);
+	if digits.len() != 6 {
+		return None;
+	}
+	let packed = u32::from_str_radix(digits, 16).ok()?;
+	let [_, r, g, b] = packed.to_be_bytes();
+	Some(RGBColour::from_rgb(r, g, b))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rgb(colour: &RGBColour) -> (u8, u8, u8) {
+		(colour.red(), colour.green(), colour.blue())
+	}
+
+	#[test]
+	fn gimp() {
+		let text = "GIMP Palette\nName: Test\nColumns: 4\n# A comment\n\n255   0   0\tRed\n  0 128 255\tSky: light\n";
+		let colours = parse(text).unwrap();
+		assert_eq!(colours.len(), 2);
+		assert_eq!(rgb(&colours[0]), (255, 0, 0));
+		assert_eq!(rgb(&colours[1]), (0, 128, 255));
+	}
+
+	#[test]
+	fn gimp_bad_line() {
+		assert!(parse("GIMP Palette\nDescription: nope\n").is_err());
+	}
+
+	#[test]
+	fn jasc() {
+		let colours = parse("JASC-PAL\n0100\n2\n1 2 3\n4 5 6\n").unwrap();
+		assert_eq!(
+			colours.iter().map(rgb).collect::<Vec<_>>(),
+			[(1, 2, 3), (4, 5, 6)]
+		);
+	}
+
+	#[test]
+	fn hex() {
+		let colours = parse("; comment\n// comment\n#102030\n0x405060\nA0B0C0\n").unwrap();
+		assert_eq!(
+			colours.iter().map(rgb).collect::<Vec<_>>(),
+			[(0x10, 0x20, 0x30), (0x40, 0x50, 0x60), (0xA0, 0xB0, 0xC0)]
+		);
+		assert!(parse("#12345\n").is_err());
+	}
+
+	#[test]
+	fn empty() {
+		assert!(parse("").is_err());
+		assert!(parse("GIMP Palette\n").is_err());
+	}
+
+	#[test]
+	fn round_trip() {
+		let colours = [
+			RGBColour::from_rgb(1, 2, 3),
+			RGBColour::from_rgb(250, 128, 0),
+		];
+		for format in [Format::Gimp, Format::Jasc, Format::Hex] {
+			let parsed = parse(&to_string(&colours, format)).unwrap();
+			assert_eq!(
+				parsed.iter().map(rgb).collect::<Vec<_>>(),
+				colours.iter().map(rgb).collect::<Vec<_>>(),
+				"{:?}",
+				format
+			);
+		}
+	}
+
+	#[test]
+	fn formats() {
+		assert_eq!(format_for_path(Path::new("a.GPL")), Format::Gimp);
+		assert_eq!(format_for_path(Path::new("a.pal")), Format::Jasc);
+		assert_eq!(format_for_path(Path::new("a.txt")), Format::Hex);
+		assert_eq!("jasc".parse::<Format>(), Ok(Format::Jasc));
+		assert_eq!("HEX".parse::<Format>(), Ok(Format::Hex));
+		assert!("png".parse::<Format>().is_err());
+	}
+}
@@ -0,0 +1,174 @@
+//! # Clipboard paste for the Neotron Desktop BIOS
+//!
+//! Types text into the OS as a series of key presses, as if someone was
+//! typing it very quickly. We assume the OS is using a US keyboard layout.
+//!
+//! If the HID queue fills up, we wait for the OS to catch up rather than
+//! dropping key presses.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::common::hid::KeyCode;
+use crate::{keymap, AppEvent};
+
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// Set while a paste is being typed.
+static PASTING: AtomicBool = AtomicBool::new(false);
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Start a thread which types `text` into the OS, at `rate` characters per
+/// second.
+///
+/// Only one paste runs at a time - if one is already going, this one is
+/// dropped.
+pub fn spawn(text: String, rate: u32, sender: mpsc::SyncSender<AppEvent>) {
+	if PASTING.swap(true, Ordering::Relaxed) {
+		warn!("Already pasting, ignoring the new paste");
+		return;
+	}
+	let half_period = Duration::from_micros(500_000 / u64::from(rate.max(1)));
+	std::thread::spawn(move || {
+		let keys = key_presses(&text);
+		info!("Pasting {} characters", keys.len());
+		for (key, shift) in keys {
+			if type_key(&sender, key, shift, half_period).is_err() {
+				break;
+			}
+		}
+		PASTING.store(false, Ordering::Relaxed);
+	});
+}
+
+/// Work out which keys to press to type `text`.
+///
+/// The `bool` says whether Shift must be held down. Characters the Neotron
+/// can't type are left out.
+fn key_presses(text: &str) -> Vec<(KeyCode, bool)> {
+	// Windows clipboards use CR LF, but that's only one press of Return
+	let text = text.replace("\r\n", "\n");
+	text.chars()
+		.filter_map(|c| {
+			let key = keymap::from_char(c);
+			if key.is_none() {
+				warn!("Can't type {:?} on the Neotron", c);
+			}
+			key
+		})
+		.collect()
+}
+
+/// Press and release a key, with Shift held around it if required.
+///
+/// The key is held down for `half_period`, and we then wait `half_period`
+/// before returning. If the HID queue is full we wait for space, behind any
+/// key releases that are waiting too. Fails if the OS has stopped taking
+/// events.
+fn type_key(
+	sender: &mpsc::SyncSender<AppEvent>,
+	key: KeyCode,
+	shift: bool,
+	half_period: Duration,
+) -> Result<(), mpsc::SendError<AppEvent>> {
+	if shift {
+		crate::send_event_waiting(sender, AppEvent::KeyDown(KeyCode::LShift))?;
+	}
+	crate::send_event_waiting(sender, AppEvent::KeyDown(key))?;
+	std::thread::sleep(half_period);
+	crate::send_event_waiting(sender, AppEvent::KeyUp(key))?;
+	if shift {
+		crate::send_event_waiting(sender, AppEvent::KeyUp(KeyCode::LShift))?;
+	}
+	std::thread::sleep(half_period);
+	Ok(())
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn shifted_punctuation() {
+		assert_eq!(
+			key_presses("a!:\"?"),
+			[
+				(KeyCode::A, false),
+				(KeyCode::Key1, true),
+				(KeyCode::Oem1, true),
+				(KeyCode::Oem3, true),
+				(KeyCode::Oem2, true),
+			]
+		);
+	}
+
+	#[test]
+	fn newlines_and_tabs() {
+		assert_eq!(
+			key_presses("x\r\ny\tz\n"),
+			[
+				(KeyCode::X, false),
+				(KeyCode::Return, false),
+				(KeyCode::Y, false),
+				(KeyCode::Tab, false),
+				(KeyCode::Z, false),
+				(KeyCode::Return, false),
+			]
+		);
+	}
+
+	#[test]
+	fn unmappable_characters_skipped() {
+		assert_eq!(
+			key_presses("café £5 ✓"),
+			[
+				(KeyCode::C, false),
+				(KeyCode::A, false),
+				(KeyCode::F, false),
+				(KeyCode::Spacebar, false),
+				(KeyCode::Key5, false),
+				(KeyCode::Spacebar, false),
+			]
+		);
+		assert_eq!(key_presses("日本"), []);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
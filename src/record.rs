@@ -0,0 +1,218 @@
+//! # Video recording for the Neotron Desktop BIOS
+//!
+//! Writes rendered frames to a raw YUV4MPEG2 (`.y4m`) stream, which `ffmpeg`
+//! can turn into something more convenient, e.g.
+//! `ffmpeg -i neotron.y4m neotron.mp4`.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::io::prelude::*;
+use std::path::Path;
+
+use log::warn;
+
+use crate::render::Frame;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Records every Nth frame to a Y4M file.
+pub struct Recorder<W: Write = std::io::BufWriter<std::fs::File>> {
+	/// Where the stream goes
+	writer: W,
+	/// We keep one frame out of this many
+	every: u32,
+	/// How many more frames to skip before we keep one
+	skip: u32,
+	/// The frame width given in the stream header
+	width: usize,
+	/// The frame height given in the stream header
+	height: usize,
+	/// Have we already complained about a frame of the wrong size?
+	warned_size: bool,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Convert an RGB pixel to BT.601 studio-range Y'CbCr.
+fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+	let (r, g, b) = (i32::from(r), i32::from(g), i32::from(b));
+	let y = (((66 * r) + (129 * g) + (25 * b) + 128) >> 8) + 16;
+	let cb = (((-38 * r) - (74 * g) + (112 * b) + 128) >> 8) + 128;
+	let cr = (((112 * r) - (94 * g) - (18 * b) + 128) >> 8) + 128;
+	(y as u8, cb as u8, cr as u8)
+}
+
+// -----------------------------------------------------------------------------
+// Impl Blocks
+// -----------------------------------------------------------------------------
+
+impl Recorder {
+	/// Start a new recording.
+	///
+	/// The stream has a fixed frame size, so we take it from the first frame.
+	/// We assume the display runs at 60 Hz, and keep one frame in every
+	/// `every`.
+	pub fn new(path: &Path, first_frame: &Frame, every: u32) -> std::io::Result<Recorder> {
+		let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+		Recorder::with_writer(writer, first_frame, every)
+	}
+}
+
+impl<W: Write> Recorder<W> {
+	/// Start a new recording, into something other than a file.
+	fn with_writer(mut writer: W, first_frame: &Frame, every: u32) -> std::io::Result<Recorder<W>> {
+		let every = every.max(1);
+		writeln!(
+			writer,
+			"YUV4MPEG2 W{} H{} F60:{} Ip A1:1 C444",
+			first_frame.width, first_frame.height, every
+		)?;
+		Ok(Recorder {
+			writer,
+			every,
+			skip: 0,
+			width: first_frame.width,
+			height: first_frame.height,
+			warned_size: false,
+		})
+	}
+
+	/// Do we want the next frame?
+	///
+	/// Lets the caller skip rendering frames we're going to throw away.
+	pub fn wants_frame(&mut self) -> bool {
+		if self.skip == 0 {
+			self.skip = self.every - 1;
+			true
+		} else {
+			self.skip -= 1;
+			false
+		}
+	}
+
+	/// Append a frame to the stream.
+	///
+	/// Frames which don't match the size of the stream (because the video
+	/// mode changed) are dropped.
+	pub fn write_frame(&mut self, frame: &Frame) -> std::io::Result<()> {
+		if frame.width != self.width || frame.height != self.height {
+			if !self.warned_size {
+				warn!(
+					"Recording is {}x{}, dropping {}x{} frames",
+					self.width, self.height, frame.width, frame.height
+				);
+				self.warned_size = true;
+			}
+			return Ok(());
+		}
+		let num_pixels = frame.width * frame.height;
+		let mut planes = vec![0u8; num_pixels * 3];
+		for (idx, rgb) in frame.pixels.chunks_exact(3).enumerate() {
+			let (y, cb, cr) = rgb_to_ycbcr(rgb[0], rgb[1], rgb[2]);
+			planes[idx] = y;
+			planes[num_pixels + idx] = cb;
+			planes[(num_pixels * 2) + idx] = cr;
+		}
+		self.writer.write_all(b"FRAME\n")?;
+		self.writer.write_all(&planes)
+	}
+
+	/// Finish the recording, flushing everything to disk.
+	pub fn finish(mut self) -> std::io::Result<()> {
+		self.writer.flush()
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A frame with the given pixels, one row high.
+	fn frame(pixels: &[u8]) -> Frame {
+		Frame {
+			width: pixels.len() / 3,
+			height: 1,
+			pixels: pixels.to_vec(),
+		}
+	}
+
+	#[test]
+	fn header() {
+		let first = Frame {
+			width: 640,
+			height: 480,
+			pixels: vec![0; 640 * 480 * 3],
+		};
+		let recorder = Recorder::with_writer(Vec::new(), &first, 2).unwrap();
+		assert_eq!(recorder.writer, b"YUV4MPEG2 W640 H480 F60:2 Ip A1:1 C444\n");
+		// Keeping every 0th frame means keeping them all
+		let recorder = Recorder::with_writer(Vec::new(), &first, 0).unwrap();
+		assert_eq!(recorder.writer, b"YUV4MPEG2 W640 H480 F60:1 Ip A1:1 C444\n");
+	}
+
+	#[test]
+	fn bt601() {
+		assert_eq!(rgb_to_ycbcr(0, 0, 0), (16, 128, 128));
+		assert_eq!(rgb_to_ycbcr(255, 255, 255), (235, 128, 128));
+		assert_eq!(rgb_to_ycbcr(255, 0, 0), (82, 90, 240));
+		assert_eq!(rgb_to_ycbcr(0, 255, 0), (144, 54, 34));
+		assert_eq!(rgb_to_ycbcr(0, 0, 255), (41, 240, 110));
+	}
+
+	#[test]
+	fn planes() {
+		let black_white = frame(&[0, 0, 0, 255, 255, 255]);
+		let mut recorder = Recorder::with_writer(Vec::new(), &black_white, 1).unwrap();
+		recorder.writer.clear();
+		recorder.write_frame(&black_white).unwrap();
+		// All the Y values, then all the Cb, then all the Cr
+		assert_eq!(recorder.writer, b"FRAME\n\x10\xEB\x80\x80\x80\x80");
+		// Frames of the wrong size are dropped
+		recorder.writer.clear();
+		recorder.write_frame(&frame(&[0, 0, 0])).unwrap();
+		assert!(recorder.writer.is_empty());
+	}
+
+	#[test]
+	fn every_third_frame() {
+		let first = frame(&[0, 0, 0]);
+		let mut recorder = Recorder::with_writer(Vec::new(), &first, 3).unwrap();
+		let wanted: Vec<bool> = (0..7).map(|_| recorder.wants_frame()).collect();
+		assert_eq!(wanted, [true, false, false, true, false, false, true]);
+		let mut recorder = Recorder::with_writer(Vec::new(), &first, 1).unwrap();
+		assert!((0..5).all(|_| recorder.wants_frame()));
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
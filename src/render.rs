@@ -0,0 +1,226 @@
+//! # Software rendering for the Neotron Desktop BIOS
+//!
+//! Turns the contents of video RAM into an RGB image in memory, without going
+//! anywhere near SDL. Used when we have no window to draw into.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::sync::atomic::Ordering;
+
+use crate::common;
+use crate::font;
+use common::video::RGBColour;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// A rendered video frame, at the native resolution of the video mode.
+#[derive(PartialEq, Eq)]
+pub struct Frame {
+	/// Width in pixels
+	pub width: usize,
+	/// Height in pixels
+	pub height: usize,
+	/// Packed 8-bit RGB triples, left-to-right and then top-to-bottom
+	pub pixels: Vec<u8>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Read the colours we use in a chunky mode with `count` colours.
+///
+/// This matches the colours the GUI uses, including the black/white special
+/// case for 1-bpp modes.
+fn chunky_colours(count: usize) -> Vec<RGBColour> {
+	let mut result: Vec<RGBColour> = crate::PALETTE
+		.iter()
+		.take(count)
+		.map(|entry| RGBColour::from_packed(entry.load(Ordering::Relaxed)))
+		.collect();
+	if count == 2 {
+		// special case - use black/white for 2 colour mode, not black/blue
+		result[1] = RGBColour::from_rgb(0xFF, 0xFF, 0xFF);
+	}
+	result
+}
+
+/// Read a single palette entry.
+fn palette_entry(index: u8) -> RGBColour {
+	RGBColour::from_packed(crate::PALETTE[usize::from(index)].load(Ordering::Relaxed))
+}
+
+// -----------------------------------------------------------------------------
+// Impl Blocks
+// -----------------------------------------------------------------------------
+
+impl Frame {
+	/// Render whatever is in video RAM right now, in the current video mode.
+	pub fn capture() -> Frame {
+		let mode_value = crate::VIDEO_MODE.load(Ordering::Relaxed);
+		// We know this is a valid video mode because it was set with `video_set_mode`.
+		let mode = unsafe { common::video::Mode::from_u8(mode_value) };
+		Self::render(mode)
+	}
+
+	/// Render whatever is in video RAM, interpreting it in the given mode.
+	pub fn render(mode: common::video::Mode) -> Frame {
+		let mut frame = Frame::new(
+			usize::from(mode.horizontal_pixels()),
+			usize::from(mode.vertical_lines()),
+		);
+		match mode.format() {
+			common::video::Format::Text8x16 => frame.render_text(
+				mode,
+				&font::Font {
+					name: "8x16 font RAM",
+					height: 16,
+					data: &crate::read_font_ram(16),
+				},
+			),
+			common::video::Format::Text8x8 => frame.render_text(
+				mode,
+				&font::Font {
+					name: "8x8 font RAM",
+					height: 8,
+					data: &crate::read_font_ram(8),
+				},
+			),
+			common::video::Format::Chunky1 => frame.render_chunky::<1>(mode),
+			common::video::Format::Chunky2 => frame.render_chunky::<2>(mode),
+			common::video::Format::Chunky4 => frame.render_chunky::<4>(mode),
+			common::video::Format::Chunky8 => frame.render_chunky::<8>(mode),
+			_ => {
+				// Unknown mode - leave it black
+			}
+		}
+		frame
+	}
+
+	/// Write this frame out as a binary PPM (P6) image.
+	pub fn write_ppm<W: std::io::Write>(&self, mut writer: W) -> std::io::Result<()> {
+		write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+		writer.write_all(&self.pixels)
+	}
+
+	/// Write this frame out as an 8-bit RGB PNG image.
+	pub fn write_png<W: std::io::Write>(&self, writer: W) -> std::io::Result<()> {
+		let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
+		encoder.set_color(png::ColorType::Rgb);
+		encoder.set_depth(png::BitDepth::Eight);
+		let mut png_writer = encoder.write_header().map_err(std::io::Error::other)?;
+		png_writer
+			.write_image_data(&self.pixels)
+			.map_err(std::io::Error::other)?;
+		png_writer.finish().map_err(std::io::Error::other)
+	}
+
+	/// Make a new black frame.
+	fn new(width: usize, height: usize) -> Frame {
+		Frame {
+			width,
+			height,
+			pixels: vec![0u8; width * height * 3],
+		}
+	}
+
+	/// Set a single pixel.
+	fn set_pixel(&mut self, x: usize, y: usize, colour: RGBColour) {
+		let offset = ((y * self.width) + x) * 3;
+		self.pixels[offset] = colour.red();
+		self.pixels[offset + 1] = colour.green();
+		self.pixels[offset + 2] = colour.blue();
+	}
+
+	/// Draw a text mode, including blinking characters and the cursor.
+	fn render_text(&mut self, mode: common::video::Mode, font: &font::Font) {
+		let num_cols = mode.text_width().unwrap();
+		let num_rows = mode.text_height().unwrap();
+		let text_blink_on = crate::text_blink_on();
+		// FRAMEBUFFER is an num_cols x num_rows size array of (u8_glyph, u8_attr).
+		for row in 0..num_rows {
+			for col in 0..num_cols {
+				let byte_offset = usize::from((row * num_cols) + col) * 2;
+				let glyph = crate::FRAMEBUFFER.get_at(byte_offset);
+				let attr = common::video::Attr(crate::FRAMEBUFFER.get_at(byte_offset + 1));
+				let fg = palette_entry(attr.fg().make_ffi_safe().0);
+				let bg = palette_entry(attr.bg().make_ffi_safe().0);
+				// Blinking characters spend half their time as just background
+				let show_glyph = text_blink_on || !attr.blink();
+				for font_y in 0..font.height {
+					let mut font_line = font.data[(usize::from(glyph) * font.height) + font_y];
+					if !show_glyph {
+						font_line = 0;
+					}
+					let y = (usize::from(row) * font.height) + font_y;
+					for font_x in 0..8 {
+						let x = (usize::from(col) * 8) + font_x;
+						let colour = if (font_line & 0x80) != 0 { fg } else { bg };
+						self.set_pixel(x, y, colour);
+						font_line <<= 1;
+					}
+				}
+			}
+		}
+
+		if let Some(cursor) = crate::CURSOR.visible_cell(num_cols, num_rows, font.height as u16) {
+			let byte_offset = usize::from((cursor.row * num_cols) + cursor.col) * 2;
+			let attr = common::video::Attr(crate::FRAMEBUFFER.get_at(byte_offset + 1));
+			let fg = palette_entry(attr.fg().make_ffi_safe().0);
+			for line in cursor.start_line..=cursor.end_line {
+				let y = usize::from((cursor.row * font.height as u16) + line);
+				for font_x in 0..8 {
+					self.set_pixel((usize::from(cursor.col) * 8) + font_x, y, fg);
+				}
+			}
+		}
+	}
+
+	/// Draw a chunky bitmap mode, with `BPP` bits per pixel.
+	fn render_chunky<const BPP: usize>(&mut self, mode: common::video::Mode) {
+		let shift = 8 - BPP;
+		let pixels_per_byte = 8 / BPP;
+		let num_col_bytes = mode.line_size_bytes();
+		let colours = chunky_colours(1 << BPP);
+		for y in 0..self.height {
+			let y_bytes = y * num_col_bytes;
+			for x_byte in 0..num_col_bytes {
+				let mut data = crate::FRAMEBUFFER.get_at(y_bytes + x_byte);
+				let x_start = x_byte * pixels_per_byte;
+				for x in 0..pixels_per_byte {
+					let bit = (data >> shift) as usize;
+					self.set_pixel(x_start + x, y, colours[bit]);
+					// In 8-bpp mode a plain shift would overflow
+					data = data.wrapping_shl(BPP as u32);
+				}
+			}
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,313 @@
+//! # Input recording and replay for the Neotron Desktop BIOS
+//!
+//! Records every HID event the OS reads, along with the tick count when it
+//! read it, so the same session can be played back later.
+//!
+//! Logs are text files with one event per line:
+//!
+//! ```text
+//! 1520 press LShift
+//! 1534 press A
+//! 1601 release A
+//! 1610 release LShift
+//! 2002 mouse 3 -1 L--
+//! ```
+//!
+//! Mouse events give the X and Y movement, then which of the left, middle and
+//! right buttons are held down. Blank lines, and lines starting with `#`, are
+//! ignored.
+//!
+//! Live input is thrown away while a replay runs. Once the last event has been
+//! sent, the real keyboard and mouse take over again.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::path::Path;
+
+use log::info;
+
+use crate::{keymap, AppEvent};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Writes events to an input log as the OS reads them.
+pub struct EventLog {
+	/// Where the log goes. Flushed after every line, so we don't lose events
+	/// if we crash.
+	writer: std::io::LineWriter<std::fs::File>,
+}
+
+/// Feeds events from an input log back to the OS.
+pub struct Replay {
+	/// The events still to come, and the tick at which to send each one
+	events: VecDeque<(u64, AppEvent)>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Turn an event into one line of an input log, without the newline.
+///
+/// Returns `None` for events that the OS never sees.
+fn format_event(ticks: u64, event: &AppEvent) -> Option<String> {
+	match event {
+		AppEvent::Started => None,
+		AppEvent::KeyDown(key) => Some(format!("{} press {:?}", ticks, key)),
+		AppEvent::KeyUp(key) => Some(format!("{} release {:?}", ticks, key)),
+		AppEvent::Mouse {
+			dx,
+			dy,
+			left,
+			middle,
+			right,
+		} => {
+			let button = |held: bool, name: char| if held { name } else { '-' };
+			Some(format!(
+				"{} mouse {} {} {}{}{}",
+				ticks,
+				dx,
+				dy,
+				button(*left, 'L'),
+				button(*middle, 'M'),
+				button(*right, 'R')
+			))
+		}
+	}
+}
+
+/// Parse one line of an input log.
+///
+/// Returns `Ok(None)` for blank lines and comments.
+fn parse_line(line: &str) -> Result<Option<(u64, AppEvent)>, &'static str> {
+	let line = line.trim();
+	if line.is_empty() || line.starts_with('#') {
+		return Ok(None);
+	}
+	let mut words = line.split_whitespace();
+	let ticks = words
+		.next()
+		.and_then(|word| word.parse::<u64>().ok())
+		.ok_or("bad tick count")?;
+	let event = match words.next() {
+		Some("press") => AppEvent::KeyDown(parse_key(words.next())?),
+		Some("release") => AppEvent::KeyUp(parse_key(words.next())?),
+		Some("mouse") => {
+			let dx = words
+				.next()
+				.and_then(|word| word.parse().ok())
+				.ok_or("bad X movement")?;
+			let dy = words
+				.next()
+				.and_then(|word| word.parse().ok())
+				.ok_or("bad Y movement")?;
+			let buttons = words.next().ok_or("missing buttons")?.as_bytes();
+			if buttons.len() != 3 {
+				return Err("buttons should look like LMR or L--");
+			}
+			AppEvent::Mouse {
+				dx,
+				dy,
+				left: buttons[0] == b'L',
+				middle: buttons[1] == b'M',
+				right: buttons[2] == b'R',
+			}
+		}
+		_ => return Err("expected press, release or mouse"),
+	};
+	if words.next().is_some() {
+		return Err("too many fields");
+	}
+	Ok(Some((ticks, event)))
+}
+
+/// Parse a key name.
+fn parse_key(word: Option<&str>) -> Result<crate::common::hid::KeyCode, &'static str> {
+	keymap::from_name(word.ok_or("missing key")?).ok_or("unknown key")
+}
+
+// -----------------------------------------------------------------------------
+// Impl Blocks
+// -----------------------------------------------------------------------------
+
+impl EventLog {
+	/// Start a new input log, replacing any file that's already there.
+	pub fn create(path: &Path) -> std::io::Result<EventLog> {
+		let mut writer = std::io::LineWriter::new(std::fs::File::create(path)?);
+		writeln!(writer, "# Neotron Desktop BIOS input log")?;
+		Ok(EventLog { writer })
+	}
+
+	/// Note that the OS read this event at this tick count.
+	pub fn write(&mut self, ticks: u64, event: &AppEvent) -> std::io::Result<()> {
+		match format_event(ticks, event) {
+			Some(line) => writeln!(self.writer, "{}", line),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Replay {
+	/// Load an input log.
+	pub fn load(path: &Path) -> std::io::Result<Replay> {
+		let text = std::fs::read_to_string(path)?;
+		let mut events = VecDeque::new();
+		for (idx, line) in text.lines().enumerate() {
+			match parse_line(line) {
+				Ok(Some(event)) => events.push_back(event),
+				Ok(None) => {}
+				Err(e) => {
+					return Err(std::io::Error::new(
+						std::io::ErrorKind::InvalidData,
+						format!("line {}: {}", idx + 1, e),
+					));
+				}
+			}
+		}
+		info!("Loaded {} events to replay", events.len());
+		Ok(Replay { events })
+	}
+
+	/// Get the next event, if it is due at or before the given tick count.
+	pub fn next_event(&mut self, ticks: u64) -> Option<AppEvent> {
+		if self.events.front()?.0 > ticks {
+			return None;
+		}
+		let (_, event) = self.events.pop_front()?;
+		Some(event)
+	}
+
+	/// Have all the events been sent?
+	pub fn is_finished(&self) -> bool {
+		self.events.is_empty()
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::common::hid::KeyCode;
+
+	#[test]
+	fn keys() {
+		assert_eq!(
+			parse_line("1534 press A"),
+			Ok(Some((1534, AppEvent::KeyDown(KeyCode::A))))
+		);
+		assert_eq!(
+			parse_line("  1601 release LShift  "),
+			Ok(Some((1601, AppEvent::KeyUp(KeyCode::LShift))))
+		);
+	}
+
+	#[test]
+	fn mouse() {
+		assert_eq!(
+			parse_line("2002 mouse 3 -1 L-R"),
+			Ok(Some((
+				2002,
+				AppEvent::Mouse {
+					dx: 3,
+					dy: -1,
+					left: true,
+					middle: false,
+					right: true,
+				}
+			)))
+		);
+	}
+
+	#[test]
+	fn comments_and_blank_lines() {
+		assert_eq!(parse_line(""), Ok(None));
+		assert_eq!(parse_line("   "), Ok(None));
+		assert_eq!(parse_line("# Neotron Desktop BIOS input log"), Ok(None));
+	}
+
+	#[test]
+	fn bad_lines() {
+		assert!(parse_line("press A").is_err());
+		assert!(parse_line("10 press").is_err());
+		assert!(parse_line("10 press Banana").is_err());
+		assert!(parse_line("10 jump A").is_err());
+		assert!(parse_line("10 press A B").is_err());
+		assert!(parse_line("10 mouse 1 2").is_err());
+		assert!(parse_line("10 mouse 1 2 LM").is_err());
+		assert!(parse_line("10 mouse x 2 ---").is_err());
+		assert!(parse_line("10 mouse 99999 2 ---").is_err());
+	}
+
+	#[test]
+	fn round_trip() {
+		let events = [
+			AppEvent::KeyDown(KeyCode::Apps),
+			AppEvent::KeyUp(KeyCode::Oem4),
+			AppEvent::Mouse {
+				dx: -200,
+				dy: 7,
+				left: false,
+				middle: true,
+				right: false,
+			},
+		];
+		for (ticks, event) in events.into_iter().enumerate() {
+			let line = format_event(ticks as u64, &event).unwrap();
+			assert_eq!(parse_line(&line), Ok(Some((ticks as u64, event))));
+		}
+	}
+
+	#[test]
+	fn started_is_not_logged() {
+		assert_eq!(format_event(0, &AppEvent::Started), None);
+	}
+
+	#[test]
+	fn replay_waits_for_each_tick() {
+		let mut replay = Replay {
+			events: VecDeque::from([
+				(10, AppEvent::KeyDown(KeyCode::A)),
+				(20, AppEvent::KeyUp(KeyCode::A)),
+			]),
+		};
+		assert_eq!(replay.next_event(9), None);
+		assert_eq!(replay.next_event(10), Some(AppEvent::KeyDown(KeyCode::A)));
+		assert!(!replay.is_finished());
+		assert_eq!(replay.next_event(15), None);
+		assert_eq!(replay.next_event(25), Some(AppEvent::KeyUp(KeyCode::A)));
+		assert!(replay.is_finished());
+		assert_eq!(replay.next_event(30), None);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
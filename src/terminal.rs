@@ -0,0 +1,605 @@
+//! # Terminal front-end for the Neotron Desktop BIOS
+//!
+//! Draws the text modes into the host terminal using ANSI escape sequences,
+//! and reads keys from the TTY. Handy when you're logged in over SSH.
+//!
+//! Terminals don't tell us when keys are released, so every key is sent to
+//! the OS as a press immediately followed by a release. We assume the OS is
+//! using a US keyboard layout.
+//!
+//! The TTY is put back how we found it when we quit, panic, or are sent
+//! `SIGINT`, `SIGTERM` or `SIGHUP`.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::io::prelude::*;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{
+	atomic::{AtomicI32, Ordering},
+	mpsc, Mutex,
+};
+
+use log::{info, warn};
+
+use crate::common::hid::KeyCode;
+use crate::{keymap, textdump, AppEvent};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// What we found at the start of an escape sequence.
+#[derive(Debug, PartialEq, Eq)]
+enum Escape {
+	/// A key, the modifiers held with it, and how many bytes it used up
+	Key(KeyCode, Vec<KeyCode>, usize),
+	/// A sequence we don't understand, and how many bytes it used up
+	Unknown(usize),
+	/// The input stops part-way through a sequence
+	Incomplete,
+}
+
+/// What we last drew into the terminal, so we only send the changes.
+#[derive(Default)]
+struct Screen {
+	/// The mode we last drew
+	mode: Option<u8>,
+	/// The (glyph, attribute) pairs we last drew, if any
+	cells: Vec<Option<(u8, u8)>>,
+	/// The text colours (the first 16 palette entries) we last drew with
+	palette: [u32; TEXT_COLOURS],
+}
+
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// The TTY settings to put back when we exit.
+static SAVED_TERMIOS: Mutex<Option<(i32, libc::termios)>> = Mutex::new(None);
+
+/// The write end of the pipe our signal handler uses to wake the signal
+/// thread, or -1 if there isn't one.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// How many palette entries the text modes use.
+const TEXT_COLOURS: usize = 16;
+
+/// Pressing Ctrl+] quits the BIOS, like it does in `telnet`.
+const QUIT_CHAR: u8 = 0x1D;
+
+/// How long to wait for the rest of an escape sequence, in milliseconds,
+/// before deciding the user just pressed Escape.
+const ESCAPE_TIMEOUT_MS: i32 = 50;
+
+/// The longest escape sequence we'll wait for, in bytes.
+const MAX_ESCAPE_LEN: usize = 32;
+
+/// Xterm-style escape sequences which end in a letter (like `ESC [ A` or
+/// `ESC O P`), by their final byte.
+static ESCAPE_LETTERS: &[(u8, KeyCode)] = &[
+	(b'A', KeyCode::ArrowUp),
+	(b'B', KeyCode::ArrowDown),
+	(b'C', KeyCode::ArrowRight),
+	(b'D', KeyCode::ArrowLeft),
+	(b'H', KeyCode::Home),
+	(b'F', KeyCode::End),
+	(b'P', KeyCode::F1),
+	(b'Q', KeyCode::F2),
+	(b'R', KeyCode::F3),
+	(b'S', KeyCode::F4),
+];
+
+/// Xterm-style escape sequences which end in `~` (like `ESC [ 5 ~`), by their
+/// first parameter.
+static ESCAPE_NUMBERS: &[(u16, KeyCode)] = &[
+	(1, KeyCode::Home),
+	(2, KeyCode::Insert),
+	(3, KeyCode::Delete),
+	(4, KeyCode::End),
+	(5, KeyCode::PageUp),
+	(6, KeyCode::PageDown),
+	(15, KeyCode::F5),
+	(17, KeyCode::F6),
+	(18, KeyCode::F7),
+	(19, KeyCode::F8),
+	(20, KeyCode::F9),
+	(21, KeyCode::F10),
+	(23, KeyCode::F11),
+	(24, KeyCode::F12),
+];
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Run the OS with the host terminal as the screen and keyboard.
+pub fn run(sender: mpsc::SyncSender<AppEvent>) -> ! {
+	info!("Running in the terminal");
+	let tty = std::fs::OpenOptions::new()
+		.read(true)
+		.write(true)
+		.open("/dev/tty")
+		.expect("open /dev/tty");
+	enter_raw_mode(&tty);
+	restore_on_panic();
+	restore_on_signals();
+
+	let input_sender = sender.clone();
+	std::thread::spawn(move || read_keys(tty, input_sender));
+
+	// Let the rest of the OS start now
+	sender.send(AppEvent::Started).unwrap();
+
+	// Switch to the alternate screen, and clear it
+	print!("\x1b[?1049h\x1b[2J");
+	let mut screen = Screen::default();
+	let frame_period = std::time::Duration::from_micros(1_000_000 / 60);
+	let mut next_frame = std::time::Instant::now();
+	loop {
+		next_frame += frame_period;
+		if let Some(delay) = next_frame.checked_duration_since(std::time::Instant::now()) {
+			std::thread::sleep(delay);
+		}
+		crate::FRAME_COUNT.fetch_add(1, Ordering::Relaxed);
+		crate::record_frame();
+		screen.draw();
+	}
+}
+
+/// Put the TTY back how we found it.
+///
+/// Safe to call even if we never touched the TTY.
+pub fn restore() {
+	if let Some((fd, termios)) = SAVED_TERMIOS.lock().unwrap().take() {
+		// Show the cursor, reset the colours and leave the alternate screen
+		print!("\x1b[?25h\x1b[0m\x1b[?1049l");
+		let _ = std::io::stdout().flush();
+		unsafe {
+			libc::tcsetattr(fd, libc::TCSANOW, &termios);
+		}
+	}
+}
+
+/// Put the TTY back how we found it, and exit.
+fn quit() -> ! {
+	restore();
+	crate::stop_recording();
+	crate::audio::stop_wav();
+	std::process::exit(0);
+}
+
+/// Put the TTY back before a panic message is printed, so it is readable.
+fn restore_on_panic() {
+	let default_hook = std::panic::take_hook();
+	std::panic::set_hook(Box::new(move |info| {
+		restore();
+		default_hook(info);
+	}));
+}
+
+/// Put the TTY back and exit if we're interrupted, killed, or the terminal
+/// goes away.
+///
+/// Very little is safe to do in a signal handler, so the handler just writes
+/// the signal number into a pipe, and a thread does the work.
+fn restore_on_signals() {
+	let mut fds = [0; 2];
+	if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+		warn!("Failed to make a pipe for signals");
+		return;
+	}
+	SIGNAL_PIPE.store(fds[1], Ordering::Relaxed);
+	for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+		unsafe {
+			libc::signal(signal, on_signal as *const () as libc::sighandler_t);
+		}
+	}
+	let mut pipe = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+	std::thread::spawn(move || {
+		let mut signal = [0u8; 1];
+		if pipe.read_exact(&mut signal).is_ok() {
+			info!("Got signal {}, quitting...", signal[0]);
+			quit();
+		}
+	});
+}
+
+/// Our signal handler. Wakes up the thread started in `restore_on_signals`.
+extern "C" fn on_signal(signal: libc::c_int) {
+	let byte = signal as u8;
+	unsafe {
+		libc::write(
+			SIGNAL_PIPE.load(Ordering::Relaxed),
+			&byte as *const u8 as *const libc::c_void,
+			1,
+		);
+	}
+}
+
+/// Stop the TTY echoing and line-buffering, so we get every key-press.
+fn enter_raw_mode(tty: &std::fs::File) {
+	let fd = tty.as_raw_fd();
+	let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+	if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+		panic!("Standard input is not a terminal");
+	}
+	let original = unsafe { termios.assume_init() };
+	let mut raw = original;
+	unsafe {
+		libc::cfmakeraw(&mut raw);
+		libc::tcsetattr(fd, libc::TCSANOW, &raw);
+	}
+	*SAVED_TERMIOS.lock().unwrap() = Some((fd, original));
+}
+
+/// Read bytes from the TTY and turn them into key presses for the OS.
+///
+/// A key can arrive split across two reads, so anything left over is kept
+/// until the next read. If nothing more turns up quickly, we decode what we
+/// have - that's how we tell Escape from the start of an escape sequence.
+fn read_keys(mut tty: std::fs::File, sender: mpsc::SyncSender<AppEvent>) {
+	let mut buffer = [0u8; 64];
+	let mut pending = Vec::new();
+	loop {
+		let timeout = if pending.is_empty() {
+			-1
+		} else {
+			ESCAPE_TIMEOUT_MS
+		};
+		let more = wait_for_input(&tty, timeout);
+		if more {
+			match tty.read(&mut buffer) {
+				Ok(0) => break,
+				Ok(n) => pending.extend_from_slice(&buffer[0..n]),
+				Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+				Err(e) => {
+					warn!("Failed to read from TTY: {:?}", e);
+					break;
+				}
+			}
+		}
+		let mut input = &pending[..];
+		while !input.is_empty() {
+			match decode_key(input, !more, &sender) {
+				Some(rest) => input = rest,
+				None => break,
+			}
+		}
+		let used = pending.len() - input.len();
+		pending.drain(0..used);
+	}
+}
+
+/// Wait up to `timeout_ms` milliseconds (or forever, if negative) for the TTY
+/// to have something to read.
+fn wait_for_input(tty: &std::fs::File, timeout_ms: i32) -> bool {
+	let mut poll_fd = libc::pollfd {
+		fd: tty.as_raw_fd(),
+		events: libc::POLLIN,
+		revents: 0,
+	};
+	unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) > 0 }
+}
+
+/// Decode one key from the start of `input`, and send it to the OS.
+///
+/// Returns whatever input is left over, or `None` if `input` stops part-way
+/// through a key. If `complete` is set, no more input is coming, so we decode
+/// whatever we have.
+fn decode_key<'a>(
+	input: &'a [u8],
+	complete: bool,
+	sender: &mpsc::SyncSender<AppEvent>,
+) -> Option<&'a [u8]> {
+	let byte = input[0];
+	let rest = &input[1..];
+	match byte {
+		QUIT_CHAR => quit(),
+		0x1B => match parse_escape(rest) {
+			Escape::Key(key, modifiers, len) => {
+				tap_key(sender, key, &modifiers);
+				Some(&rest[len..])
+			}
+			Escape::Unknown(len) => {
+				warn!("Ignoring unknown escape sequence {:02x?}", &input[0..=len]);
+				Some(&rest[len..])
+			}
+			Escape::Incomplete if !complete => None,
+			Escape::Incomplete => {
+				// Just the Escape key, or a sequence we gave up waiting for
+				tap_key(sender, KeyCode::Escape, &[]);
+				Some(rest)
+			}
+		},
+		b'\r' | b'\n' | b'\t' | 0x08 | 0x7F => {
+			if let Some((key, shift)) = keymap::from_char(char::from(byte)) {
+				tap_key(sender, key, shift_held(shift));
+			}
+			Some(rest)
+		}
+		0x00..=0x1F => {
+			if let Some(key) = keymap::from_control_char(byte) {
+				tap_key(sender, key, &[KeyCode::LControl]);
+			}
+			Some(rest)
+		}
+		_ => {
+			// Decode one UTF-8 character
+			let len = match byte {
+				0xC0..=0xDF => 2,
+				0xE0..=0xEF => 3,
+				0xF0..=0xF7 => 4,
+				_ => 1,
+			};
+			if len > input.len() && !complete {
+				return None;
+			}
+			let len = len.min(input.len());
+			let typed = std::str::from_utf8(&input[0..len])
+				.ok()
+				.and_then(|s| s.chars().next())
+				.and_then(keymap::from_char);
+			match typed {
+				Some((key, shift)) => tap_key(sender, key, shift_held(shift)),
+				None => warn!("Can't type {:02x?} on the Neotron", &input[0..len]),
+			}
+			Some(&input[len..])
+		}
+	}
+}
+
+/// Parse the escape sequence in `rest`, which follows an Escape byte.
+///
+/// We understand `ESC O <letter>` and xterm's `ESC [ <params> <final>`,
+/// including the modifier parameter in sequences like `ESC [ 1 ; 5 A` (which
+/// is Ctrl+Up). Anything else after an Escape is the Escape key on its own.
+fn parse_escape(rest: &[u8]) -> Escape {
+	match rest.first() {
+		None => Escape::Incomplete,
+		Some(b'O') => match rest.get(1) {
+			None => Escape::Incomplete,
+			Some(final_byte) => match letter_key(*final_byte) {
+				Some(key) => Escape::Key(key, Vec::new(), 2),
+				None => Escape::Unknown(2),
+			},
+		},
+		Some(b'[') => {
+			// Parameter and intermediate bytes, then one final byte
+			let Some(len) = rest[1..]
+				.iter()
+				.position(|byte| !(0x20..=0x3F).contains(byte))
+			else {
+				if rest.len() > MAX_ESCAPE_LEN {
+					return Escape::Unknown(rest.len());
+				}
+				return Escape::Incomplete;
+			};
+			let final_byte = rest[1 + len];
+			if !(0x40..=0x7E).contains(&final_byte) {
+				// Not a proper sequence - drop it, but keep the odd byte
+				return Escape::Unknown(1 + len);
+			}
+			let params: Vec<u16> = std::str::from_utf8(&rest[1..1 + len])
+				.unwrap_or("")
+				.split(';')
+				.map(|param| param.parse().unwrap_or(0))
+				.collect();
+			let key = if final_byte == b'~' {
+				ESCAPE_NUMBERS
+					.iter()
+					.find(|(number, _)| *number == params[0])
+					.map(|(_, key)| *key)
+			} else {
+				letter_key(final_byte)
+			};
+			let modifier = params.get(1).copied().unwrap_or(1);
+			match key {
+				Some(key) => Escape::Key(key, modifiers(modifier), len + 2),
+				None => Escape::Unknown(len + 2),
+			}
+		}
+		Some(_) => Escape::Key(KeyCode::Escape, Vec::new(), 0),
+	}
+}
+
+/// Find the key for an escape sequence ending in a letter.
+fn letter_key(final_byte: u8) -> Option<KeyCode> {
+	ESCAPE_LETTERS
+		.iter()
+		.find(|(letter, _)| *letter == final_byte)
+		.map(|(_, key)| *key)
+}
+
+/// Turn xterm's modifier parameter (one more than a bitmap of Shift, Alt and
+/// Ctrl) into the keys to hold down.
+fn modifiers(param: u16) -> Vec<KeyCode> {
+	let bits = param.saturating_sub(1);
+	let mut keys = Vec::new();
+	if bits & 1 != 0 {
+		keys.push(KeyCode::LShift);
+	}
+	if bits & 2 != 0 {
+		keys.push(KeyCode::LAlt);
+	}
+	if bits & 4 != 0 {
+		keys.push(KeyCode::LControl);
+	}
+	keys
+}
+
+/// The modifiers to hold for a character that may need Shift.
+fn shift_held(shift: bool) -> &'static [KeyCode] {
+	if shift {
+		&[KeyCode::LShift]
+	} else {
+		&[]
+	}
+}
+
+/// Send a key press and release, with some modifiers held around it.
+///
+/// These go through the HID queue like any other key, so if the OS isn't
+/// reading it, presses are dropped rather than stopping us reading stdin.
+fn tap_key(sender: &mpsc::SyncSender<AppEvent>, key: KeyCode, modifiers: &[KeyCode]) {
+	for modifier in modifiers {
+		crate::send_event(sender, AppEvent::KeyDown(*modifier));
+	}
+	crate::send_event(sender, AppEvent::KeyDown(key));
+	crate::send_event(sender, AppEvent::KeyUp(key));
+	for modifier in modifiers.iter().rev() {
+		crate::send_event(sender, AppEvent::KeyUp(*modifier));
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Impl Blocks
+// -----------------------------------------------------------------------------
+
+impl Screen {
+	/// Bring the terminal up to date with video RAM.
+	fn draw(&mut self) {
+		let mode_value = crate::VIDEO_MODE.load(Ordering::Relaxed);
+		// We know this is a valid video mode because it was set with `video_set_mode`.
+		let mode = unsafe { crate::common::video::Mode::from_u8(mode_value) };
+		let text_size = textdump::text_size(mode);
+		let mut output = String::new();
+		if self.mode != Some(mode_value) {
+			self.mode = Some(mode_value);
+			self.cells.clear();
+			output.push_str("\x1b[0m\x1b[2J");
+			if text_size.is_none() {
+				output.push_str(&format!(
+					"\x1b[H\x1b[?25lVideo mode {} can't be shown in a terminal",
+					mode_value
+				));
+			}
+		}
+
+		let Some((num_cols, num_rows)) = text_size else {
+			print!("{}", output);
+			let _ = std::io::stdout().flush();
+			return;
+		};
+
+		// The colours are sent with every cell, so a new palette means
+		// drawing everything again
+		let mut palette = [0; TEXT_COLOURS];
+		for (colour, entry) in palette.iter_mut().zip(crate::PALETTE.iter()) {
+			*colour = entry.load(Ordering::Relaxed);
+		}
+		if palette != self.palette {
+			self.palette = palette;
+			self.cells.clear();
+		}
+
+		self.cells
+			.resize(usize::from(num_cols) * usize::from(num_rows), None);
+		let mut last_attr = None;
+		let mut last_pos = None;
+		for row in 0..num_rows {
+			for col in 0..num_cols {
+				let (glyph, attr) = textdump::cell(num_cols, row, col);
+				let idx = (usize::from(row) * usize::from(num_cols)) + usize::from(col);
+				if self.cells[idx] == Some((glyph, attr.as_u8())) {
+					continue;
+				}
+				self.cells[idx] = Some((glyph, attr.as_u8()));
+				if last_pos != Some((row, col)) {
+					output.push_str(&format!("\x1b[{};{}H", row + 1, col + 1));
+				}
+				if last_attr != Some(attr.as_u8()) {
+					output.push_str(&textdump::sgr(attr));
+					last_attr = Some(attr.as_u8());
+				}
+				output.push(textdump::CP850[usize::from(glyph)]);
+				last_pos = Some((row, col + 1));
+			}
+		}
+
+		// Put the terminal's cursor where the hardware cursor is
+		match crate::CURSOR.position(num_cols, num_rows) {
+			Some((col, row)) => {
+				output.push_str(&format!("\x1b[{};{}H\x1b[?25h", row + 1, col + 1));
+			}
+			None => output.push_str("\x1b[?25l"),
+		}
+
+		print!("{}", output);
+		let _ = std::io::stdout().flush();
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn plain_sequences() {
+		assert_eq!(
+			parse_escape(b"[Axyz"),
+			Escape::Key(KeyCode::ArrowUp, vec![], 2)
+		);
+		assert_eq!(parse_escape(b"OQ"), Escape::Key(KeyCode::F2, vec![], 2));
+		assert_eq!(parse_escape(b"[24~"), Escape::Key(KeyCode::F12, vec![], 4));
+	}
+
+	#[test]
+	fn modified_sequences() {
+		assert_eq!(
+			parse_escape(b"[1;5A"),
+			Escape::Key(KeyCode::ArrowUp, vec![KeyCode::LControl], 5)
+		);
+		assert_eq!(
+			parse_escape(b"[3;4~"),
+			Escape::Key(KeyCode::Delete, vec![KeyCode::LShift, KeyCode::LAlt], 5)
+		);
+	}
+
+	#[test]
+	fn unknown_sequences_are_swallowed() {
+		assert_eq!(parse_escape(b"[1;5Zabc"), Escape::Unknown(5));
+		assert_eq!(parse_escape(b"[99~"), Escape::Unknown(4));
+	}
+
+	#[test]
+	fn split_sequences() {
+		assert_eq!(parse_escape(b""), Escape::Incomplete);
+		assert_eq!(parse_escape(b"["), Escape::Incomplete);
+		assert_eq!(parse_escape(b"[1;5"), Escape::Incomplete);
+		assert_eq!(parse_escape(b"O"), Escape::Incomplete);
+	}
+
+	#[test]
+	fn lone_escape() {
+		assert_eq!(parse_escape(b"a"), Escape::Key(KeyCode::Escape, vec![], 0));
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
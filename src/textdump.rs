@@ -0,0 +1,275 @@
+//! # Text-mode screen dumps for the Neotron Desktop BIOS
+//!
+//! Turns the contents of a text mode into a string, either as plain UTF-8 or
+//! with ANSI escape sequences for the colours.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+use crate::common;
+use common::video::RGBColour;
+
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// The Unicode equivalent of each glyph in our code-page 850 fonts.
+///
+/// The control characters (0x00 to 0x1F, and 0x7F) map to the symbols the
+/// font actually draws for them, as on an IBM PC.
+pub static CP850: [char; 256] = [
+	// 0x00
+	' ', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+	// 0x10
+	'►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+	// 0x20
+	' ', '!', '"', '#', '$', '%', '&', '\'', '(', ')', '*', '+', ',', '-', '.', '/',
+	// 0x30
+	'0', '1', '2', '3', '4', '5', '6', '7', '8', '9', ':', ';', '<', '=', '>', '?',
+	// 0x40
+	'@', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O',
+	// 0x50
+	'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '[', '\\', ']', '^', '_',
+	// 0x60
+	'`', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o',
+	// 0x70
+	'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '{', '|', '}', '~', '⌂',
+	// 0x80
+	'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+	// 0x90
+	'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', 'ø', '£', 'Ø', '×', 'ƒ',
+	// 0xA0
+	'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '®', '¬', '½', '¼', '¡', '«', '»',
+	// 0xB0
+	'░', '▒', '▓', '│', '┤', 'Á', 'Â', 'À', '©', '╣', '║', '╗', '╝', '¢', '¥', '┐',
+	// 0xC0
+	'└', '┴', '┬', '├', '─', '┼', 'ã', 'Ã', '╚', '╔', '╩', '╦', '╠', '═', '╬', '¤',
+	// 0xD0
+	'ð', 'Ð', 'Ê', 'Ë', 'È', 'ı', 'Í', 'Î', 'Ï', '┘', '┌', '█', '▄', '¦', 'Ì', '▀',
+	// 0xE0
+	'Ó', 'ß', 'Ô', 'Ò', 'õ', 'Õ', 'µ', 'þ', 'Þ', 'Ú', 'Û', 'Ù', 'ý', 'Ý', '¯', '´',
+	// 0xF0
+	'\u{AD}', '±', '‗', '¾', '¶', '§', '÷', '¸', '°', '¨', '·', '¹', '³', '²', '■', '\u{A0}',
+];
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Dump the screen as plain UTF-8 text, one line per text row.
+///
+/// Trailing spaces are trimmed from each line. Returns `None` if the given
+/// mode isn't a text mode.
+pub fn to_utf8(mode: common::video::Mode) -> Option<String> {
+	let (num_cols, num_rows) = text_size(mode)?;
+	Some(utf8_lines(num_cols, num_rows, |row, col| {
+		cell(num_cols, row, col)
+	}))
+}
+
+/// Dump the screen as text with ANSI 24-bit colour escape sequences.
+///
+/// Colours come from the live palette, and blinking characters use the ANSI
+/// blink attribute. Each line ends by resetting the attributes. Returns `None`
+/// if the given mode isn't a text mode.
+pub fn to_ansi(mode: common::video::Mode) -> Option<String> {
+	let (num_cols, num_rows) = text_size(mode)?;
+	Some(ansi_lines(
+		num_cols,
+		num_rows,
+		|row, col| cell(num_cols, row, col),
+		palette_entry,
+	))
+}
+
+/// Turn the (glyph, attribute) pairs given by `cell(row, col)` into plain
+/// UTF-8 text, one line per row, with trailing spaces trimmed.
+fn utf8_lines<F>(num_cols: u16, num_rows: u16, cell: F) -> String
+where
+	F: Fn(u16, u16) -> (u8, common::video::Attr),
+{
+	let mut result = String::new();
+	for row in 0..num_rows {
+		let line: String = (0..num_cols)
+			.map(|col| CP850[usize::from(cell(row, col).0)])
+			.collect();
+		result.push_str(line.trim_end_matches(' '));
+		result.push('\n');
+	}
+	result
+}
+
+/// Turn the (glyph, attribute) pairs given by `cell(row, col)` into text with
+/// ANSI colours, one line per row, using `colour` to look up palette entries.
+fn ansi_lines<F, C>(num_cols: u16, num_rows: u16, cell: F, colour: C) -> String
+where
+	F: Fn(u16, u16) -> (u8, common::video::Attr),
+	C: Fn(u8) -> RGBColour,
+{
+	let mut result = String::new();
+	for row in 0..num_rows {
+		let mut last_attr = None;
+		for col in 0..num_cols {
+			let (glyph, attr) = cell(row, col);
+			if last_attr != Some(attr.as_u8()) {
+				result.push_str(&sgr_with(attr, &colour));
+				last_attr = Some(attr.as_u8());
+			}
+			result.push(CP850[usize::from(glyph)]);
+		}
+		result.push_str("\x1b[0m\n");
+	}
+	result
+}
+
+/// Make the ANSI 'Select Graphic Rendition' sequence for a text attribute.
+pub fn sgr(attr: common::video::Attr) -> String {
+	sgr_with(attr, palette_entry)
+}
+
+/// Make the ANSI 'Select Graphic Rendition' sequence for a text attribute,
+/// using `colour` to look up palette entries.
+fn sgr_with<C>(attr: common::video::Attr, colour: C) -> String
+where
+	C: Fn(u8) -> RGBColour,
+{
+	let fg = colour(attr.fg().make_ffi_safe().0);
+	let bg = colour(attr.bg().make_ffi_safe().0);
+	let mut result = String::from("\x1b[0");
+	if attr.blink() {
+		result.push_str(";5");
+	}
+	let _ = write!(
+		result,
+		";38;2;{};{};{};48;2;{};{};{}m",
+		fg.red(),
+		fg.green(),
+		fg.blue(),
+		bg.red(),
+		bg.green(),
+		bg.blue()
+	);
+	result
+}
+
+/// Get the number of columns and rows in a text mode.
+pub fn text_size(mode: common::video::Mode) -> Option<(u16, u16)> {
+	Some((mode.text_width()?, mode.text_height()?))
+}
+
+/// Read a (glyph, attribute) pair from the text-mode framebuffer.
+pub fn cell(num_cols: u16, row: u16, col: u16) -> (u8, common::video::Attr) {
+	let byte_offset = usize::from((row * num_cols) + col) * 2;
+	let glyph = crate::FRAMEBUFFER.get_at(byte_offset);
+	let attr = common::video::Attr(crate::FRAMEBUFFER.get_at(byte_offset + 1));
+	(glyph, attr)
+}
+
+/// Read a single palette entry.
+fn palette_entry(index: u8) -> RGBColour {
+	RGBColour::from_packed(crate::PALETTE[usize::from(index)].load(Ordering::Relaxed))
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::video::Attr;
+
+	/// A palette where entry `n` is (n, n * 2, n * 3).
+	fn test_colour(index: u8) -> RGBColour {
+		RGBColour::from_rgb(index, index * 2, index * 3)
+	}
+
+	#[test]
+	fn cp850() {
+		assert_eq!(CP850[usize::from(b'A')], 'A');
+		assert_eq!(CP850[0x01], '☺');
+		// Box drawing
+		assert_eq!(CP850[0xB3], '│');
+		assert_eq!(CP850[0xC4], '─');
+		assert_eq!(CP850[0xC9], '╔');
+		assert_eq!(CP850[0xBC], '╝');
+		assert_eq!(CP850[0xDB], '█');
+		// Accented letters
+		assert_eq!(CP850[0x82], 'é');
+		assert_eq!(CP850[0x9A], 'Ü');
+		assert_eq!(CP850[0xA4], 'ñ');
+		assert_eq!(CP850[0xE1], 'ß');
+		assert_eq!(CP850[0xB7], 'À');
+	}
+
+	#[test]
+	fn utf8_rows() {
+		let rows = [b"Hi\xC9\xCD  ", b"      ", b" \x82 x  "];
+		let text = utf8_lines(6, 3, |row, col| {
+			(rows[usize::from(row)][usize::from(col)], Attr(0x07))
+		});
+		// Trailing spaces go, but leading ones and blank rows stay
+		assert_eq!(text, "Hi╔═\n\n é x\n");
+	}
+
+	#[test]
+	fn ansi_rows() {
+		let attrs = [0x07, 0x07, 0x1F];
+		let text = ansi_lines(
+			3,
+			1,
+			|_, col| (b'a' + col as u8, Attr(attrs[usize::from(col)])),
+			test_colour,
+		);
+		assert_eq!(
+			text,
+			"\x1b[0;38;2;7;14;21;48;2;0;0;0mab\x1b[0;38;2;15;30;45;48;2;1;2;3mc\x1b[0m\n"
+		);
+	}
+
+	#[test]
+	fn sgr_colours() {
+		// White on black
+		assert_eq!(
+			sgr_with(Attr(0x07), test_colour),
+			"\x1b[0;38;2;7;14;21;48;2;0;0;0m"
+		);
+		// Bright yellow on red
+		assert_eq!(
+			sgr_with(Attr(0x4E), test_colour),
+			"\x1b[0;38;2;14;28;42;48;2;4;8;12m"
+		);
+		// Blinking
+		assert_eq!(
+			sgr_with(Attr(0xF0), test_colour),
+			"\x1b[0;5;38;2;0;0;0;48;2;7;14;21m"
+		);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,313 @@
+//! # WAV files for the Neotron Desktop BIOS
+//!
+//! Writes and reads plain PCM `.wav` files, in the Neotron's sample formats.
+//!
+//! WAV files store 8-bit samples as unsigned, but the Neotron uses signed
+//! 8-bit samples, so we convert them on the way through.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2023
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use crate::audio;
+use crate::common::audio::SampleFormat;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Writes samples to a WAV file.
+///
+/// The sizes in the header are kept up to date after every write, so the file
+/// is still playable if we never get to call `finish`.
+pub struct WavWriter {
+	/// Where the file goes
+	writer: std::io::BufWriter<std::fs::File>,
+	/// The format of the samples we're given
+	format: SampleFormat,
+	/// How many bytes of samples we've written
+	data_bytes: u32,
+}
+
+/// The contents of a WAV file.
+pub struct WavData {
+	/// How many channels there are (1 or 2)
+	pub channels: u8,
+	/// How many sample frames per second
+	pub sample_rate_hz: u32,
+	/// The samples, converted to 16-bit and interleaved
+	pub samples: Vec<i16>,
+}
+
+// -----------------------------------------------------------------------------
+// Global Variables
+// -----------------------------------------------------------------------------
+
+/// The size of a WAV header, up to the start of the sample data.
+const HEADER_BYTES: u32 = 44;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Read a mono or stereo, 8-bit or 16-bit, PCM WAV file.
+pub fn read(path: &Path) -> std::io::Result<WavData> {
+	parse(&std::fs::read(path)?)
+		.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Parse the contents of a WAV file.
+fn parse(data: &[u8]) -> Result<WavData, &'static str> {
+	if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+		return Err("not a WAV file");
+	}
+	let mut format = None;
+	let mut rest = &data[12..];
+	while rest.len() >= 8 {
+		let id = &rest[0..4];
+		let size = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+		let body = rest.get(8..8 + size).unwrap_or(&rest[8..]);
+		match id {
+			b"fmt " if body.len() >= 16 => {
+				let tag = u16::from_le_bytes([body[0], body[1]]);
+				let channels = u16::from_le_bytes([body[2], body[3]]);
+				let rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+				let bits = u16::from_le_bytes([body[14], body[15]]);
+				if tag != 1 {
+					return Err("only PCM WAV files are supported");
+				}
+				if !(1..=2).contains(&channels) {
+					return Err("only mono and stereo WAV files are supported");
+				}
+				format = Some((channels as u8, rate, bits));
+			}
+			b"data" => {
+				let (channels, sample_rate_hz, bits) = format.ok_or("data before format")?;
+				let samples = match bits {
+					8 => body
+						.iter()
+						.map(|byte| i16::from(*byte as i8 ^ -128) << 8)
+						.collect(),
+					16 => body
+						.chunks_exact(2)
+						.map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+						.collect(),
+					_ => return Err("only 8-bit and 16-bit WAV files are supported"),
+				};
+				return Ok(WavData {
+					channels,
+					sample_rate_hz,
+					samples,
+				});
+			}
+			_ => {}
+		}
+		// Chunks are padded to an even length
+		let skip = (8 + size + (size & 1)).min(rest.len());
+		rest = &rest[skip..];
+	}
+	Err("no sample data")
+}
+
+// -----------------------------------------------------------------------------
+// Impl Blocks
+// -----------------------------------------------------------------------------
+
+impl WavWriter {
+	/// Create a WAV file, replacing any file that's already there.
+	pub fn create(
+		path: &Path,
+		format: SampleFormat,
+		sample_rate_hz: u32,
+	) -> std::io::Result<WavWriter> {
+		let channels = u16::from(audio::channels(format));
+		let frame_bytes = audio::frame_bytes(format) as u16;
+		let bits = audio::sample_bytes(format) as u16 * 8;
+		let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+		writer.write_all(b"RIFF")?;
+		writer.write_all(&(HEADER_BYTES - 8).to_le_bytes())?;
+		writer.write_all(b"WAVEfmt ")?;
+		writer.write_all(&16u32.to_le_bytes())?;
+		// PCM
+		writer.write_all(&1u16.to_le_bytes())?;
+		writer.write_all(&channels.to_le_bytes())?;
+		writer.write_all(&sample_rate_hz.to_le_bytes())?;
+		writer.write_all(&(sample_rate_hz * u32::from(frame_bytes)).to_le_bytes())?;
+		writer.write_all(&frame_bytes.to_le_bytes())?;
+		writer.write_all(&bits.to_le_bytes())?;
+		writer.write_all(b"data")?;
+		writer.write_all(&0u32.to_le_bytes())?;
+		writer.flush()?;
+		Ok(WavWriter {
+			writer,
+			format,
+			data_bytes: 0,
+		})
+	}
+
+	/// Append some samples, in the Neotron's format.
+	pub fn write_samples(&mut self, samples: &[u8]) -> std::io::Result<()> {
+		if audio::sample_bytes(self.format) == 1 {
+			let unsigned: Vec<u8> = samples.iter().map(|byte| byte ^ 0x80).collect();
+			self.writer.write_all(&unsigned)?;
+		} else {
+			self.writer.write_all(samples)?;
+		}
+		self.data_bytes = self.data_bytes.saturating_add(samples.len() as u32);
+		self.update_header()
+	}
+
+	/// Finish the file, flushing everything to disk.
+	pub fn finish(mut self) -> std::io::Result<()> {
+		self.update_header()
+	}
+
+	/// Write the current sizes into the header.
+	fn update_header(&mut self) -> std::io::Result<()> {
+		self.writer.seek(SeekFrom::Start(4))?;
+		self.writer.write_all(
+			&(HEADER_BYTES - 8)
+				.saturating_add(self.data_bytes)
+				.to_le_bytes(),
+		)?;
+		self.writer
+			.seek(SeekFrom::Start(u64::from(HEADER_BYTES) - 4))?;
+		self.writer.write_all(&self.data_bytes.to_le_bytes())?;
+		self.writer.seek(SeekFrom::End(0))?;
+		self.writer.flush()
+	}
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Make a PCM WAV file, with an extra chunk before the format.
+	fn wav(channels: u16, rate: u32, bits: u16, data: &[u8]) -> Vec<u8> {
+		let mut file = Vec::new();
+		file.extend(b"RIFF");
+		file.extend((4 + 14 + 24 + 8 + data.len() as u32).to_le_bytes());
+		file.extend(b"WAVE");
+		// An odd-sized chunk, which has a padding byte
+		file.extend(b"LIST");
+		file.extend(5u32.to_le_bytes());
+		file.extend(b"hello\0");
+		file.extend(b"fmt ");
+		file.extend(16u32.to_le_bytes());
+		file.extend(1u16.to_le_bytes());
+		file.extend(channels.to_le_bytes());
+		file.extend(rate.to_le_bytes());
+		let frame_bytes = channels * bits / 8;
+		file.extend((rate * u32::from(frame_bytes)).to_le_bytes());
+		file.extend(frame_bytes.to_le_bytes());
+		file.extend(bits.to_le_bytes());
+		file.extend(b"data");
+		file.extend((data.len() as u32).to_le_bytes());
+		file.extend(data);
+		file
+	}
+
+	#[test]
+	fn sixteen_bit_stereo() {
+		let data = parse(&wav(
+			2,
+			44100,
+			16,
+			&[0x01, 0x00, 0xFF, 0xFF, 0x00, 0x80, 0xFF, 0x7F],
+		))
+		.unwrap();
+		assert_eq!(data.channels, 2);
+		assert_eq!(data.sample_rate_hz, 44100);
+		assert_eq!(data.samples, [1, -1, i16::MIN, i16::MAX]);
+	}
+
+	#[test]
+	fn eight_bit_mono() {
+		let data = parse(&wav(1, 8000, 8, &[0x80, 0xFF, 0x00])).unwrap();
+		assert_eq!(data.channels, 1);
+		assert_eq!(data.sample_rate_hz, 8000);
+		assert_eq!(data.samples, [0, 127 << 8, i16::MIN]);
+	}
+
+	#[test]
+	fn truncated_data() {
+		// The data chunk claims more than there is, so take what's there
+		let mut file = wav(1, 8000, 16, &[0x01, 0x00, 0x02, 0x00]);
+		file.truncate(file.len() - 1);
+		let data = parse(&file).unwrap();
+		assert_eq!(data.samples, [1]);
+	}
+
+	#[test]
+	fn bad_files() {
+		assert_eq!(parse(b"RIFF").err(), Some("not a WAV file"));
+		assert_eq!(parse(b"RIFF\0\0\0\0AVI LIST").err(), Some("not a WAV file"));
+		assert_eq!(
+			parse(&wav(3, 8000, 16, &[])).err(),
+			Some("only mono and stereo WAV files are supported")
+		);
+		assert_eq!(
+			parse(&wav(1, 8000, 24, &[0; 3])).err(),
+			Some("only 8-bit and 16-bit WAV files are supported")
+		);
+		let mut float = wav(1, 8000, 32, &[0; 4]);
+		// The format tag is just after the LIST chunk and the "fmt " header
+		float[12 + 14 + 8] = 3;
+		assert_eq!(
+			parse(&float).err(),
+			Some("only PCM WAV files are supported")
+		);
+		let mut no_data = wav(1, 8000, 16, &[]);
+		no_data.truncate(no_data.len() - 8);
+		assert_eq!(parse(&no_data).err(), Some("no sample data"));
+		assert_eq!(
+			parse(b"RIFF\0\0\0\0WAVEdata\0\0\0\0").err(),
+			Some("data before format")
+		);
+	}
+
+	#[test]
+	fn round_trip() {
+		let path =
+			std::env::temp_dir().join(format!("neotron-wav-test-{}.wav", std::process::id()));
+		let mut writer = WavWriter::create(&path, SampleFormat::SixteenBitStereo, 48000).unwrap();
+		writer.write_samples(&[0x34, 0x12, 0xCC, 0xED]).unwrap();
+		writer.finish().unwrap();
+		let data = read(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(data.channels, 2);
+		assert_eq!(data.sample_rate_hz, 48000);
+		assert_eq!(data.samples, [0x1234, -0x1234]);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------